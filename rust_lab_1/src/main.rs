@@ -1,63 +1,153 @@
-use std::{fs::File, io::{self, Write}};
+use std::{collections::HashMap, fmt, fs::{self, OpenOptions}, io::{self, Write},
+          num::ParseIntError, ops::Mul, path::{Path, PathBuf},
+          sync::{OnceLock, atomic::{AtomicU32, Ordering}}, time::{SystemTime, UNIX_EPOCH}};
 use rand::Rng;
 
 fn main() {
+    let (start, steps) = max_stopping_time(1000, 1000);
+    println!("Longest stopping time up to 1000: {} steps for {}", steps, start);
+    println!("Cycle of 5x + 1 from 13: {:?}", find_cycle(13, 5, 1, 100));
 
-    let result = loop{
+    // Batch mode: cargo run -- <input file> <output file>
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, input, output] = args.as_slice() {
+        match run_batch(Path::new(input), Path::new(output)) {
+            Ok(()) => println!("Batch written to {}", output),
+            Err(error) => println!("Batch failed: {}", error),
+        }
+        return;
+    }
+
+    match run(Path::new("rounds")) {
+        Ok(()) => println!("Loop ended because user wanted it to end."),
+        Err(error) => {
+            println!("{}", error);
+            println!("Loop ended because of error.")
+        }
+    }
+}
+
+#[derive(Debug)]
+enum GameError {
+    ParseFailed(String),
+    IoFailed(io::Error),
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GameError::ParseFailed(msg) => write!(f, "{}", msg),
+            GameError::IoFailed(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<io::Error> for GameError {
+    fn from(error: io::Error) -> Self {
+        GameError::IoFailed(error)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Continue {
+    Yes,
+    No,
+}
+
+// Every round is saved to its own file in `out_dir`.
+fn run(out_dir: &Path) -> Result<(), GameError> {
+    loop {
         let mut guess = String::new();
         println!("Enter the number!");
-        io::stdin().read_line(&mut guess).expect("Failed to read line");
+        io::stdin().read_line(&mut guess)?;
 
-        let mut number: u64 = match guess.trim().parse() {
-            Ok(num) => num,
-            Err(error) => {
-                println!("{}", error);
-                break true;
-            }
-        };
-
-        if number == 0 {
-            break false;
-        }
-        
-        number += rand::thread_rng().gen_range(0..=5);
-        println!("New x value: {}", number);
-
-        let array:[u64; 10] = pow_table(number);
-        println!("{:?}", array);
-        let mut collatz_res_arr = [false; 10];
-        for i in 0..10 {
-            collatz_res_arr[i] = is_collatz(array[i], 100);
-        } 
-        println!("{:?}", collatz_res_arr);
-
-        let (desc, avg, has_prime) = analyze_results(array);
-        println!("Description: {desc}, Average: {avg}, Has prime: {has_prime}");
-
-        match save_to_file(collatz_res_arr, "xyz.txt".to_string()) {
-            Ok(..) => continue,
-            Err(error) => {
-                println!("{}", error);
-                break true;
-            }
-        };
-    };
+        let offset = rand::thread_rng().gen_range(0..=5);
+        if play_round(&guess, offset, out_dir)? == Continue::No {
+            return Ok(());
+        }
+    }
+}
+
+fn play_round(input: &str, offset: u64, out_dir: &Path) -> Result<Continue, GameError> {
+    let mut number = parse_number(input)?;
 
-    if result {
-        println!("Loop ended because of error.")
+    if number == 0 {
+        return Ok(Continue::No);
     }
-    else {
-        println!("Loop ended because user wanted it to end.")
+
+    number += offset;
+    println!("New x value: {}", number);
+
+    let round = analyze_round(number);
+    println!("{:?}", round.powers);
+    println!("{:?}", round.collatz);
+    println!("Description: {}, Average: {}, Has prime: {}", round.desc, round.avg, round.has_prime);
+
+    let path = save_to_file(round.collatz, out_dir)?;
+    println!("Saved to {}", path.display());
+    Ok(Continue::Yes)
+}
+
+fn parse_number(input: &str) -> Result<u64, GameError> {
+    input.trim().parse()
+        .map_err(|error: ParseIntError| GameError::ParseFailed(error.to_string()))
+}
+
+// Everything computed for one number, without printing or saving it.
+struct Round {
+    number: u64,
+    powers: [u64; 10],
+    collatz: [bool; 10],
+    desc: String,
+    avg: f64,
+    has_prime: bool,
+}
+
+impl fmt::Display for Round {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: collatz {:?}, {}, average {}, has prime {}",
+               self.number, self.collatz, self.desc, self.avg, self.has_prime)
     }
+}
 
+fn analyze_round(number: u64) -> Round {
+    let powers: [u64; 10] = pow_table(number);
+    let mut collatz = [false; 10];
+    for i in 0..10 {
+        collatz[i] = is_collatz(powers[i], 100);
+    }
+    let (desc, avg, has_prime) = analyze_results(powers);
+    Round { number, powers, collatz, desc, avg, has_prime }
+}
+
+// Rounds are played without the random offset, one record per valid line is appended to the output.
+fn run_batch(input_path: &Path, output_path: &Path) -> io::Result<()> {
+    let input = fs::read_to_string(input_path)?;
+    let mut output = OpenOptions::new().create(true).append(true).open(output_path)?;
+
+    for (i, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_number(line) {
+            Ok(number) => writeln!(output, "{}", analyze_round(number))?,
+            Err(error) => eprintln!("Skipping line {} ({:?}): {}", i + 1, line, error),
+        }
+    }
+    Ok(())
 }
 
 fn pow_table<const LEN: usize>(x: u64) -> [u64; LEN] {
+    pow_table_t(x)
+}
+
+fn pow_table_t<T, const LEN: usize>(x: T) -> [T; LEN]
+where
+    T: Copy + Mul<Output = T>,
+{
     let mut arr = [x; LEN];
-    let mut val = x;
-    for item in arr.iter_mut() {
-        *item = val;
-        val *= x;
+    for i in 1..LEN {
+        arr[i] = arr[i - 1] * x;
     }
     arr
 }
@@ -79,8 +169,99 @@ fn collatz(x: u64) -> u64 {
     x/2
 }
 
-fn save_to_file(arr: [bool; 10], file_name: String) -> io::Result<()>{
-    let mut file = File::create(file_name).expect("Unable to create or open file.");
+// Halves even numbers and maps odd ones to ax + b, None on overflow.
+fn collatz_general(x: u64, a: u64, b: u64) -> Option<u64> {
+    if x % 2 == 1 {
+        return a.checked_mul(x)?.checked_add(b);
+    }
+    Some(x/2)
+}
+
+// The cycle reached from `start` within `limit` steps, rotated to begin at its smallest value.
+fn find_cycle(start: u64, a: u64, b: u64, limit: u32) -> Option<Vec<u64>> {
+    let mut seen = HashMap::new();
+    let mut path = Vec::new();
+    let mut x = start;
+    for _ in 0..=limit {
+        if let Some(&first) = seen.get(&x) {
+            let mut cycle: Vec<u64> = path.split_off(first);
+            let smallest = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap_or(0);
+            cycle.rotate_left(smallest);
+            return Some(cycle);
+        }
+        seen.insert(x, path.len());
+        path.push(x);
+        x = collatz_general(x, a, b)?;
+    }
+    None
+}
+
+// Collatz stopping times found so far, shared between queries.
+#[derive(Default)]
+struct StoppingTimes {
+    known: HashMap<u64, u32>,
+}
+
+impl StoppingTimes {
+    // Steps needed to reach 1, None if it takes more than `limit`.
+    fn get(&mut self, start: u64, limit: u32) -> Option<u32> {
+        let mut path = Vec::new();
+        let mut x = start;
+        let mut tail = 0;
+        while x != 1 {
+            if let Some(&known) = self.known.get(&x) {
+                tail = known;
+                break;
+            }
+            if path.len() as u32 >= limit {
+                return None;
+            }
+            path.push(x);
+            x = collatz(x);
+        }
+
+        let total = path.len() as u32 + tail;
+        for (i, &value) in path.iter().enumerate() {
+            self.known.insert(value, total - i as u32);
+        }
+        (total <= limit).then_some(total)
+    }
+}
+
+// Starts whose stopping time exceeds `limit` are skipped, ties go to the smallest start.
+fn max_stopping_time(upto: u64, limit: u32) -> (u64, u32) {
+    let mut times = StoppingTimes::default();
+    let mut best: Option<(u64, u32)> = None;
+    for start in 1..=upto {
+        if let Some(steps) = times.get(start, limit)
+            && best.is_none_or(|(_, best_steps)| steps > best_steps) {
+            best = Some((start, steps));
+        }
+    }
+    best.unwrap_or((0, 0))
+}
+
+// Numbers the files saved during this run, together with the start time it keeps names unique.
+static SAVED_FILES: AtomicU32 = AtomicU32::new(0);
+
+// Seconds since the Unix epoch at the first save of this run.
+static STARTED: OnceLock<u64> = OnceLock::new();
+
+// Writes to a new file in `dir`, existing files are never overwritten.
+fn save_to_file(arr: [bool; 10], dir: &Path) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let started = *STARTED.get_or_init(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+    });
+    let (path, mut file) = loop {
+        let n = SAVED_FILES.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("round_{}_{}.txt", started, n));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => break (path, file),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error),
+        }
+    };
     let mut text = String::new();
 
     for value in arr.iter() {
@@ -92,8 +273,8 @@ fn save_to_file(arr: [bool; 10], file_name: String) -> io::Result<()>{
         text.pop();
     }
 
-    file.write_all(text.as_bytes()).expect("Unable to write to file.");
-    Ok(())
+    file.write_all(text.as_bytes())?;
+    Ok(path)
 }
 
 fn analyze_results(values: [u64;10]) -> (String, f64, bool) {
@@ -138,4 +319,130 @@ fn analyze_results(values: [u64;10]) -> (String, f64, bool) {
     };
 
     (desc, sum as f64 / values.len() as f64, found_prime)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pow_table_t_f64() {
+        let table: [f64; 3] = pow_table_t(2.0);
+        assert_eq!(table, [2.0, 4.0, 8.0]);
+    }
+
+    #[test]
+    fn pow_table_t_u128_beyond_u64() {
+        let table: [u128; 5] = pow_table_t(10_000);
+        assert_eq!(table[4], 10_000u128.pow(5));
+        assert!(table[4] > u64::MAX as u128);
+    }
+
+    #[test]
+    fn pow_table_t_i64_and_matches_pow_table() {
+        let table: [i64; 4] = pow_table_t(-3);
+        assert_eq!(table, [-3, 9, -27, 81]);
+        let u: [u64; 10] = pow_table(3);
+        assert_eq!(u, pow_table_t::<u64, 10>(3));
+    }
+
+    #[test]
+    fn stopping_times_are_memoized() {
+        let mut times = StoppingTimes::default();
+        assert_eq!(times.get(1, 0), Some(0));
+        assert_eq!(times.get(6, 100), Some(8));
+        assert_eq!(times.known.get(&3), Some(&7));
+        assert_eq!(times.get(3, 6), None);
+        assert_eq!(times.get(0, 50), None);
+    }
+
+    #[test]
+    fn max_stopping_time_small_ranges() {
+        assert_eq!(max_stopping_time(9, 100), (9, 19));
+        // 18 and 19 both need 20 steps
+        assert_eq!(max_stopping_time(19, 100), (18, 20));
+        assert_eq!(max_stopping_time(10, 10), (6, 8));
+        assert_eq!(max_stopping_time(1, 0), (1, 0));
+        assert_eq!(max_stopping_time(0, 100), (0, 0));
+    }
+
+    #[test]
+    fn find_cycle_standard_rule() {
+        assert_eq!(find_cycle(6, 3, 1, 100), Some(vec![1, 4, 2]));
+        assert_eq!(find_cycle(1, 3, 1, 3), Some(vec![1, 4, 2]));
+        // 27 needs 111 steps to reach 1
+        assert_eq!(find_cycle(27, 3, 1, 50), None);
+    }
+
+    #[test]
+    fn find_cycle_generalized_rules() {
+        assert_eq!(find_cycle(13, 5, 1, 100),
+                   Some(vec![13, 66, 33, 166, 83, 416, 208, 104, 52, 26]));
+        // 7 under 5x + 1 keeps growing
+        assert_eq!(find_cycle(7, 5, 1, 60), None);
+        assert_eq!(collatz_general(u64::MAX, 3, 1), None);
+        assert_eq!(find_cycle(u64::MAX, 3, 1, 10), None);
+    }
+
+    #[test]
+    fn run_batch_appends_one_record_per_number() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("rust_lab_1_{}_batch_in.txt", std::process::id()));
+        let output = dir.join(format!("rust_lab_1_{}_batch_out.txt", std::process::id()));
+        fs::write(&input, "2\nthree\n3\n\n5\n").unwrap();
+        let _ = fs::remove_file(&output);
+
+        run_batch(&input, &output).unwrap();
+        let written = fs::read_to_string(&output).unwrap();
+        let records: Vec<&str> = written.lines().collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0], analyze_round(2).to_string());
+        assert!(records[1].starts_with("3: collatz [true, "));
+        assert!(records[2].starts_with("5: "));
+
+        run_batch(&input, &output).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap().lines().count(), 6);
+        fs::remove_file(input).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn play_round_rejects_non_numeric_input() {
+        let dir = Path::new("unused");
+        assert!(matches!(play_round("abc\n", 0, dir), Err(GameError::ParseFailed(_))));
+        assert!(matches!(play_round("", 0, dir), Err(GameError::ParseFailed(_))));
+        assert!(matches!(play_round("-3", 0, dir), Err(GameError::ParseFailed(_))));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn play_round_stops_on_zero() {
+        assert!(matches!(play_round("0\n", 3, Path::new("unused")), Ok(Continue::No)));
+    }
+
+    #[test]
+    fn each_round_gets_its_own_file() {
+        let dir = std::env::temp_dir().join(format!("rust_lab_1_{}_rounds", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert!(matches!(play_round("2\n", 0, &dir), Ok(Continue::Yes)));
+        assert!(matches!(play_round("2\n", 1, &dir), Ok(Continue::Yes)));
+
+        let mut contents: Vec<String> = fs::read_dir(&dir).unwrap()
+            .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        contents.sort();
+        let expected = |n| analyze_round(n).collatz.map(|b| b.to_string()).join(",");
+        let mut wanted = vec![expected(2), expected(3)];
+        wanted.sort();
+        assert_eq!(contents, wanted);
+
+        // every file of the run carries the same start time
+        let started: Vec<String> = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().split('_').nth(1).unwrap()
+                .to_string())
+            .collect();
+        assert_eq!(started.len(), 2);
+        assert_eq!(started[0], started[1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -32,16 +32,19 @@ Answer:
     str_slice is already &str, so we pass it directly.
 */
 
+use std::collections::BTreeMap;
+
 fn main() {
     // Ex. 5-7
-    let a = NumberWithUnit::unitless(12.5);
+    let a: NumberWithUnit = 12.5.into();
     let mut s1 = NumberWithUnit::with_unit(13.0, String::from("km"));
     let c = NumberWithUnit::with_unit_from(s1.clone(), 14.0);
     println!("a: {:?}", a);
+    println!("-a: {:?}, |-a|: {:?}", -a.clone(), (-a.clone()).abs());
     println!("s1: {:?}", s1);
     println!("b: {:?}", c);
 
-    let s2 = NumberWithUnit::with_unit(17.0, String::from("km"));
+    let s2: NumberWithUnit = (17.0, "km").into();
     let t = NumberWithUnit::with_unit(2.0, String::from("h"));
 
     let mut s3 = NumberWithUnit::with_unit(10.0, String::from("cm"));
@@ -55,9 +58,19 @@ fn main() {
 
     s1.add_in_place(&s2);
     println!("Po add in place dla s1: {:?}", s1);
-    let v = s1.div(t);
+    let v = s1.div(t.clone());
     println!("Speed: {:?}", v);
 
+    let km_h = NumberWithUnit::with_unit(6.0, String::from("km")).mul(t.clone());
+    let h_km = t.mul(NumberWithUnit::with_unit(1.0, String::from("km")));
+    let km_h_sum = km_h.add(h_km);
+    println!("km*h + h*km: {:?}", km_h_sum);
+    println!("Approximately 8 h*km: {}",
+             km_h_sum.approx_eq(&NumberWithUnit::with_unit(8.0, String::from("h*km")), 1e-9));
+
+    let token = v.to_token();
+    println!("Speed as token: {}, parsed back: {:?}", token, NumberWithUnit::from_token(&token));
+
     // Ex. 8-10
     let measurements = Vec::from(
         [NumberWithUnit::with_unit(5.5, String::from("m")),
@@ -65,7 +78,11 @@ fn main() {
             NumberWithUnit::with_unit(10.0, String::from("m"))
         ]);
 
+    println!("{:?}", NumberWithUnit::add_many(&measurements));
+    println!("{:?}", NumberWithUnit::subtotals_by_unit(&[measurements[0].clone(), 2.5.into()]));
     println!("{:?}", mul_vals(&measurements[0..2]));
+    println!("Sum: {:?}", measurements.iter().cloned().sum::<NumberWithUnit>());
+    println!("Product: {:?}", measurements.iter().cloned().product::<NumberWithUnit>());
     println!("{:?}", mul_vals_vec(measurements.clone()));
     println!("{:?}", mul_vals_vec(measurements));
 
@@ -83,12 +100,24 @@ fn main() {
     double_string_2.show();
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 struct NumberWithUnit {
     unit: String,
     value: f64,
 }
 
+#[derive(Debug, PartialEq)]
+enum ParseError {
+    MissingSeparator,
+    BadValue(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct UnitMismatch {
+    expected: String,
+    found: String,
+}
+
 impl NumberWithUnit {
     fn unitless(value: f64) -> Self {
         Self { value, unit: String::new() }
@@ -102,8 +131,35 @@ impl NumberWithUnit {
         Self {value, unit: other.unit.clone()}
     }
 
+    fn to_token(&self) -> String {
+        format!("{}|{}", self.value, self.unit)
+    }
+
+    fn from_token(s: &str) -> Result<Self, ParseError> {
+        let (value, unit) = s.split_once('|').ok_or(ParseError::MissingSeparator)?;
+        let value = value.parse()
+            .map_err(|_| ParseError::BadValue(value.to_string()))?;
+        Ok(Self::with_unit(value, unit.to_string()))
+    }
+
+    fn same_unit(&self, other: &Self) -> bool {
+        canonicalize(&self.unit) == canonicalize(&other.unit)
+    }
+
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        self.same_unit(other) && (self.value - other.value).abs() <= epsilon
+    }
+
+    fn neg(self) -> Self {
+        Self {value: -self.value, unit: self.unit}
+    }
+
+    fn abs(self) -> Self {
+        Self {value: self.value.abs(), unit: self.unit}
+    }
+
     fn add(self, other: Self) -> Self {
-        if self.unit == other.unit {
+        if self.same_unit(&other) {
             let val = self.value + other.value;
             NumberWithUnit::with_unit_from(self, val)
         }
@@ -112,16 +168,40 @@ impl NumberWithUnit {
         }
     }
 
+    // The total keeps the unit of the first value, the count helps computing an average.
+    fn add_many(values: &[Self]) -> Result<(Self, usize), UnitMismatch> {
+        let Some(first) = values.first() else {
+            return Ok((Self::default(), 0));
+        };
+        let mut total = first.clone();
+        for x in &values[1..] {
+            if !total.same_unit(x) {
+                return Err(UnitMismatch {expected: total.unit, found: x.unit.clone()});
+            }
+            total.value += x.value;
+        }
+        Ok((total, values.len()))
+    }
+
+    // Sums per canonical unit instead of failing on a mismatch, unitless values go under "".
+    fn subtotals_by_unit(values: &[Self]) -> BTreeMap<String, f64> {
+        let mut subtotals = BTreeMap::new();
+        for x in values {
+            *subtotals.entry(canonicalize(&x.unit)).or_insert(0.0) += x.value;
+        }
+        subtotals
+    }
+
     fn mul(self, other: Self) -> Self {
         Self {value: self.value * other.value, unit: [self.unit, other.unit].join("*")}
     }
 
     fn div(self, other: Self) -> Self {
-        Self {value: self.value / other.value, unit: [self.unit, other.unit].join("/")}
+        Self {value: self.value / other.value, unit: quotient_unit(&self.unit, &other.unit)}
     }
 
     fn add_in_place(&mut self, other: &Self) {
-        if self.unit == other.unit {
+        if self.same_unit(other) {
             self.value += other.value;
         }
         else {
@@ -136,10 +216,102 @@ impl NumberWithUnit {
 
     fn div_in_place(&mut self, other: &Self) {
         self.value /= other.value;
-        self.unit = [self.unit.clone(), other.unit.clone()].join("/");
+        self.unit = quotient_unit(&self.unit, &other.unit);
     }
 }
 
+impl std::ops::Neg for NumberWithUnit {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        NumberWithUnit::neg(self)
+    }
+}
+
+// Same as folding with add, panics on mixed units. An empty sum is the default value.
+impl std::iter::Sum for NumberWithUnit {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.reduce(Self::add).unwrap_or_default()
+    }
+}
+
+// Same result as mul_vals.
+impl std::iter::Product for NumberWithUnit {
+    fn product<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.reduce(Self::mul).unwrap_or_default()
+    }
+}
+
+impl From<f64> for NumberWithUnit {
+    fn from(value: f64) -> Self {
+        Self::unitless(value)
+    }
+}
+
+impl From<(f64, &str)> for NumberWithUnit {
+    fn from((value, unit): (f64, &str)) -> Self {
+        Self::with_unit(value, unit.to_string())
+    }
+}
+
+// Splits a unit like "km*h/s" into numerator and denominator base units.
+fn unit_factors(unit: &str) -> (Vec<&str>, Vec<&str>) {
+    let mut numerator = Vec::new();
+    let mut denominator = Vec::new();
+    let mut in_denominator = false;
+    let mut start = 0;
+    for (i, c) in unit.char_indices().chain([(unit.len(), '*')]) {
+        if c == '*' || c == '/' {
+            let base = &unit[start..i];
+            if !base.is_empty() {
+                if in_denominator {
+                    denominator.push(base);
+                } else {
+                    numerator.push(base);
+                }
+            }
+            in_denominator = c == '/';
+            start = i + 1;
+        }
+    }
+    (numerator, denominator)
+}
+
+// Sorts both sides and cancels base units present in the numerator and the denominator.
+fn render_unit(mut numerator: Vec<&str>, mut denominator: Vec<&str>) -> String {
+    numerator.sort();
+    denominator.sort();
+    numerator.retain(|base| {
+        match denominator.iter().position(|d| d == base) {
+            Some(i) => {
+                denominator.remove(i);
+                false
+            }
+            None => true,
+        }
+    });
+
+    let mut canonical = numerator.join("*");
+    for base in denominator {
+        canonical.push('/');
+        canonical.push_str(base);
+    }
+    canonical
+}
+
+fn canonicalize(unit: &str) -> String {
+    let (numerator, denominator) = unit_factors(unit);
+    render_unit(numerator, denominator)
+}
+
+fn quotient_unit(dividend: &str, divisor: &str) -> String {
+    let (mut numerator, mut denominator) = unit_factors(dividend);
+    let (divisor_numerator, divisor_denominator) = unit_factors(divisor);
+    numerator.extend(divisor_denominator);
+    denominator.extend(divisor_numerator);
+    render_unit(numerator, denominator)
+}
+
 fn mul_vals(slice: &[NumberWithUnit]) -> NumberWithUnit {
     if slice.is_empty() {
         return NumberWithUnit::default();
@@ -187,6 +359,7 @@ impl DoubleString {
         Self(str_1.to_string(), str_2.to_string())
     }
 
+    #[allow(clippy::ptr_arg)] // &String is the point of exercise 15 (b)
     fn from_strings(str_1: &String, str_2: &String) -> Self {
         Self(str_1.clone(), str_2.clone())
     }
@@ -194,4 +367,212 @@ impl DoubleString {
     fn show(&self) {
         println!("({}, {})", self.0, self.1);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neg_and_abs_keep_unit() {
+        let three = NumberWithUnit::with_unit(3.0, String::from("m"));
+        let minus_three = NumberWithUnit::with_unit(-3.0, String::from("m"));
+        assert_eq!(three.clone().neg(), minus_three);
+        assert_eq!(-three.clone(), minus_three);
+        assert_eq!(minus_three.clone().abs(), three);
+        assert_eq!(three.clone().abs(), three);
+        assert_eq!(-(-three.clone()), three);
+    }
+
+    #[test]
+    fn from_conversions() {
+        let x: NumberWithUnit = 3.0.into();
+        assert_eq!(x.value, 3.0);
+        assert_eq!(x.unit, "");
+        assert_eq!(x, NumberWithUnit::unitless(3.0));
+
+        let y: NumberWithUnit = (3.0, "km").into();
+        assert_eq!(y.value, 3.0);
+        assert_eq!(y.unit, "km");
+        assert_eq!(NumberWithUnit::from((0.5, "m/s")),
+                   NumberWithUnit::with_unit(0.5, String::from("m/s")));
+    }
+
+    #[test]
+    fn canonicalize_sorts_base_units() {
+        assert_eq!(canonicalize("km*h"), "h*km");
+        assert_eq!(canonicalize("h*km"), "h*km");
+        assert_eq!(canonicalize("m/s*kg"), "kg*m/s");
+        assert_eq!(canonicalize(""), "");
+        assert_eq!(canonicalize("m*s/s"), "m");
+        assert_eq!(canonicalize("cm/cm"), "");
+    }
+
+    #[test]
+    fn div_cancels_identical_units() {
+        let a = NumberWithUnit::with_unit(10.0, String::from("cm"));
+        let b = NumberWithUnit::with_unit(2.0, String::from("cm"));
+        assert_eq!(a.clone().div(b.clone()), NumberWithUnit::unitless(5.0));
+
+        let mut c = a;
+        c.div_in_place(&b);
+        assert_eq!(c, NumberWithUnit::unitless(5.0));
+    }
+
+    #[test]
+    fn div_cancels_common_base_units() {
+        let a = NumberWithUnit::with_unit(6.0, String::from("m*s"));
+        let b = NumberWithUnit::with_unit(2.0, String::from("s"));
+        assert_eq!(a.div(b), NumberWithUnit::with_unit(3.0, String::from("m")));
+
+        let mut speed = NumberWithUnit::with_unit(100.0, String::from("km"));
+        speed.div_in_place(&NumberWithUnit::with_unit(2.0, String::from("h")));
+        assert_eq!(speed.unit, "km/h");
+        speed.div_in_place(&NumberWithUnit::with_unit(5.0, String::from("km/h")));
+        assert_eq!(speed, NumberWithUnit::unitless(10.0));
+    }
+
+    #[test]
+    fn add_accepts_reordered_units() {
+        let a = NumberWithUnit::with_unit(6.0, String::from("km*h"));
+        let b = NumberWithUnit::with_unit(2.0, String::from("h*km"));
+        let sum = a.add(b);
+        assert_eq!(sum.value, 8.0);
+        assert_eq!(canonicalize(&sum.unit), "h*km");
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let values = [
+            NumberWithUnit::unitless(12.5),
+            NumberWithUnit::unitless(-0.1),
+            NumberWithUnit::with_unit(13.0, String::from("km")),
+            NumberWithUnit::with_unit(1.0 / 3.0, String::from("km/h")),
+            NumberWithUnit::with_unit(1e-12, String::from("cm*cm/h")),
+            NumberWithUnit::default(),
+        ];
+        for x in values {
+            assert_eq!(NumberWithUnit::from_token(&x.to_token()), Ok(x));
+        }
+    }
+
+    #[test]
+    fn token_format() {
+        assert_eq!(NumberWithUnit::with_unit(2.5, String::from("m")).to_token(), "2.5|m");
+        assert_eq!(NumberWithUnit::unitless(3.0).to_token(), "3|");
+    }
+
+    #[test]
+    fn from_token_errors() {
+        assert_eq!(NumberWithUnit::from_token("3 m"), Err(ParseError::MissingSeparator));
+        assert_eq!(NumberWithUnit::from_token("three|m"),
+                   Err(ParseError::BadValue(String::from("three"))));
+    }
+
+    #[test]
+    fn add_many_sums_and_counts() {
+        let values = [
+            NumberWithUnit::with_unit(1.5, String::from("m")),
+            NumberWithUnit::with_unit(2.0, String::from("m")),
+            NumberWithUnit::with_unit(0.5, String::from("m")),
+        ];
+        let (total, count) = NumberWithUnit::add_many(&values).unwrap();
+        assert_eq!(total, NumberWithUnit::with_unit(4.0, String::from("m")));
+        assert_eq!(count, 3);
+        assert_eq!(NumberWithUnit::add_many(&[]), Ok((NumberWithUnit::default(), 0)));
+    }
+
+    #[test]
+    fn add_many_rejects_mixed_units() {
+        let values = [
+            NumberWithUnit::with_unit(1.0, String::from("m")),
+            NumberWithUnit::with_unit(2.0, String::from("s")),
+            NumberWithUnit::with_unit(3.0, String::from("m")),
+        ];
+        assert_eq!(NumberWithUnit::add_many(&values),
+                   Err(UnitMismatch {expected: String::from("m"), found: String::from("s")}));
+    }
+
+    #[test]
+    fn subtotals_by_unit_groups_mixed_units() {
+        let values = [
+            NumberWithUnit::with_unit(1.5, String::from("m")),
+            NumberWithUnit::with_unit(2.0, String::from("km")),
+            NumberWithUnit::unitless(4.0),
+            NumberWithUnit::with_unit(0.5, String::from("m")),
+            NumberWithUnit::unitless(1.0),
+        ];
+        let subtotals = NumberWithUnit::subtotals_by_unit(&values);
+        assert_eq!(subtotals, BTreeMap::from([
+            (String::new(), 5.0),
+            (String::from("km"), 2.0),
+            (String::from("m"), 2.0),
+        ]));
+        assert!(NumberWithUnit::subtotals_by_unit(&[]).is_empty());
+    }
+
+    #[test]
+    fn subtotals_by_unit_canonicalizes() {
+        let values = [
+            NumberWithUnit::with_unit(1.0, String::from("km*h")),
+            NumberWithUnit::with_unit(2.0, String::from("h*km")),
+        ];
+        assert_eq!(NumberWithUnit::subtotals_by_unit(&values),
+                   BTreeMap::from([(String::from("h*km"), 3.0)]));
+    }
+
+    #[test]
+    fn approx_eq_tolerates_rounding() {
+        let a = NumberWithUnit::with_unit(0.1, String::from("m"));
+        let b = NumberWithUnit::with_unit(0.2, String::from("m"));
+        let expected = NumberWithUnit::with_unit(0.3, String::from("m"));
+        let sum = a.add(b);
+        assert_ne!(sum, expected);
+        assert!(sum.approx_eq(&expected, 1e-12));
+        assert!(!sum.approx_eq(&expected, 1e-20));
+    }
+
+    #[test]
+    fn approx_eq_compares_canonical_units() {
+        let a = NumberWithUnit::with_unit(2.0, String::from("km*h"));
+        assert!(a.approx_eq(&NumberWithUnit::with_unit(2.05, String::from("h*km")), 0.1));
+        assert!(!a.approx_eq(&NumberWithUnit::with_unit(2.0, String::from("km")), 0.1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_rejects_different_units() {
+        let a = NumberWithUnit::with_unit(6.0, String::from("km"));
+        let b = NumberWithUnit::with_unit(2.0, String::from("h"));
+        a.add(b);
+    }
+
+    #[test]
+    fn sum_of_measurements() {
+        let measurements = vec![NumberWithUnit::with_unit(5.5, String::from("m")),
+                                NumberWithUnit::with_unit(3.0, String::from("m")),
+                                NumberWithUnit::with_unit(1.5, String::from("m"))];
+        let total: NumberWithUnit = measurements.into_iter().sum();
+        assert_eq!(total, NumberWithUnit::with_unit(10.0, String::from("m")));
+        let empty: NumberWithUnit = Vec::new().into_iter().sum();
+        assert_eq!(empty, NumberWithUnit::default());
+    }
+
+    #[test]
+    #[should_panic]
+    fn sum_rejects_different_units() {
+        let _: NumberWithUnit = [NumberWithUnit::from((1.0, "m")), NumberWithUnit::from((1.0, "s"))]
+            .into_iter().sum();
+    }
+
+    #[test]
+    fn product_matches_mul_vals() {
+        let measurements = vec![NumberWithUnit::with_unit(2.0, String::from("m")),
+                                NumberWithUnit::with_unit(3.0, String::from("m")),
+                                NumberWithUnit::with_unit(0.5, String::from("s"))];
+        let expected = mul_vals(&measurements);
+        let product: NumberWithUnit = measurements.into_iter().product();
+        assert_eq!(product, NumberWithUnit::with_unit(3.0, String::from("m*m*s")));
+        assert_eq!(product, expected);
+    }
+}
@@ -1,34 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::rc::Rc;
 
-#[derive(Copy, Debug, PartialEq, Clone)]
+#[derive(Copy, Debug, PartialEq, Eq, Hash, Clone)]
 enum Var {
     X,
     Y,
     Z,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum Const {
     Numeric(i64),
     Named(String),
+    Float(f64),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum E {
     Add(Box<E>, Box<E>),
+    Sub(Box<E>, Box<E>),
     Neg(Box<E>),
     Mul(Box<E>, Box<E>),
     Inv(Box<E>),
     Const(Const),
     Func {name: String, arg: Box<E>},
     Var(Var),
+    Pow(Box<E>, u32),
+}
+
+// Same tree as E, but children are shared so diff doesn't deep-clone f and g.
+#[derive(Debug)]
+enum ERc {
+    Add(Rc<ERc>, Rc<ERc>),
+    Sub(Rc<ERc>, Rc<ERc>),
+    Neg(Rc<ERc>),
+    Mul(Rc<ERc>, Rc<ERc>),
+    Inv(Rc<ERc>),
+    Const(Const),
+    Func {name: String, arg: Rc<ERc>},
+    Var(Var),
+    Pow(Rc<ERc>, u32),
+}
+
+#[derive(Debug, PartialEq)]
+enum EvalError {
+    UnboundVar(Var),
+    UnboundConst(String),
+    UnknownFunc(String),
 }
 
+// Function name that isn't an identifier.
+#[derive(Debug, PartialEq)]
+struct BadName(String);
+
+// Pieces of the Display form of E, in the order they are printed.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Op(&'static str),
+    Ident(String),
+    Num(i64),
+    Float(f64),
+}
+
+// How to_string_with writes E, the default gives the same text as Display. Without redundant
+// parens a subexpression is only wrapped where precedence requires it.
+#[derive(Debug, Clone)]
+struct DisplayOptions {
+    mul_symbol: &'static str,
+    redundant_parens: bool,
+    inverse_as_power: bool,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        Self {mul_symbol: " * ", redundant_parens: true, inverse_as_power: false}
+    }
+}
+
+// Tree deeper than the limit given to with_max_depth.
+#[derive(Debug, PartialEq)]
+struct TooDeep {
+    depth: usize,
+    max: usize,
+}
+
+// Rewrites a node for which `matches` holds into what `build` makes of it.
+#[derive(Clone, Copy)]
+struct RewriteRule {
+    matches: fn(&E) -> bool,
+    build: fn(E) -> Box<E>,
+}
+
+// The identities simplify applies, as rules for apply_rules. fold_node reads them from here.
+const IDENTITY_RULES: [RewriteRule; 9] = [
+    // x + 0, 0 + x
+    RewriteRule {
+        matches: |e| matches!(e, E::Add(a, b) if a.is_zero() || b.is_zero()),
+        build: |e| match e {
+            E::Add(a, b) => if a.is_zero() { b } else { a },
+            e => Box::new(e),
+        },
+    },
+    // x - 0
+    RewriteRule {
+        matches: |e| matches!(e, E::Sub(_, b) if b.is_zero()),
+        build: |e| match e {
+            E::Sub(a, _) => a,
+            e => Box::new(e),
+        },
+    },
+    // 0 - x
+    RewriteRule {
+        matches: |e| matches!(e, E::Sub(a, _) if a.is_zero()),
+        build: |e| match e {
+            E::Sub(_, b) => E::neg(b),
+            e => Box::new(e),
+        },
+    },
+    // x * 0, 0 * x
+    RewriteRule {
+        matches: |e| matches!(e, E::Mul(a, b) if a.is_zero() || b.is_zero()),
+        build: |_| E::constant(Const::Numeric(0)),
+    },
+    // x * 1, 1 * x
+    RewriteRule {
+        matches: |e| matches!(e, E::Mul(a, b) if a.is_one() || b.is_one()),
+        build: |e| match e {
+            E::Mul(a, b) => if a.is_one() { b } else { a },
+            e => Box::new(e),
+        },
+    },
+    // --x
+    RewriteRule {
+        matches: |e| matches!(e, E::Neg(inner) if matches!(**inner, E::Neg(_))),
+        build: |e| e.unpack_neg_neg().expect("matched a double negation"),
+    },
+    // 1/(1/x)
+    RewriteRule {
+        matches: |e| matches!(e, E::Inv(inner) if matches!(**inner, E::Inv(_))),
+        build: |e| e.unpack_inv_inv().expect("matched a double inverse"),
+    },
+    // x^0
+    RewriteRule {
+        matches: |e| matches!(e, E::Pow(_, 0)),
+        build: |_| E::constant(Const::Numeric(1)),
+    },
+    // x^1
+    RewriteRule {
+        matches: |e| matches!(e, E::Pow(_, 1)),
+        build: |e| match e {
+            E::Pow(a, _) => a,
+            e => Box::new(e),
+        },
+    },
+];
+
 impl E {
     fn add(arg1: Box<Self>, arg2:  Box<Self>) -> Box<Self> {
         Box::new(Self::Add(arg1, arg2))
     }
 
+    fn sub(arg1: Box<Self>, arg2:  Box<Self>) -> Box<Self> {
+        Box::new(Self::Sub(arg1, arg2))
+    }
+
     fn var(arg1: Var) -> Box<Self> {
         Box::new(Self::Var(arg1))
     }
@@ -53,17 +191,37 @@ impl E {
         Box::new(Self::Func { name, arg })
     }
 
+    // Like func, but only for names made of letters, digits and '_' not starting with a digit.
+    fn try_func(name: &str, arg: Box<Self>) -> Result<Box<Self>, BadName> {
+        let valid = name.chars().next().is_some_and(|c| !c.is_numeric())
+            && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if !valid {
+            return Err(BadName(name.to_string()));
+        }
+        Ok(Self::func(name.to_string(), arg))
+    }
+
+    fn pow(base: Box<Self>, exp: u32) -> Box<Self> {
+        Box::new(Self::Pow(base, exp))
+    }
+
     fn arg_count(&self) -> u32 {
         match &self {
-            E::Add(_, _) | E::Mul(_, _) => 2,
+            E::Add(_, _) | E::Sub(_, _) | E::Mul(_, _) => 2,
             E::Const(_) | E::Var(_) => 0,
             _ => 1,
         }
     }
 
+    // diff applied n times, each step simplified so the intermediates stay small.
+    fn diff_n(self, by: Var, n: u32) -> Box<Self> {
+        (0..n).fold(Box::new(self), |e, _| e.diff(by).simplify())
+    }
+
     fn diff(self, by: Var) -> Box<Self> {
         match self {
             Self::Add(e1, e2) => Self::add(e1.diff(by), e2.diff(by)),
+            Self::Sub(e1, e2) => Self::sub(e1.diff(by), e2.diff(by)),
             Self::Neg(e) => Self::neg(e.diff(by)),
             Self::Mul(e1, e2) => {
                 let f = e1.clone();
@@ -92,6 +250,13 @@ impl E {
                 let arg_diff = arg.diff(by);
                 Self::mul(f_diff, arg_diff)
             },
+            Self::Pow(_, 0) => Self::constant(Const::Numeric(0)),
+            Self::Pow(e, n) => {
+                let f = e.clone();
+                let f_prime = e.diff(by);
+                Self::mul(Self::mul(Self::constant(Const::Numeric(n as i64)), Self::pow(f, n - 1)),
+                          f_prime)
+            }
         }
     }
 
@@ -126,6 +291,8 @@ impl E {
         match self {
             Self::Add(e1, e2) => Self::add(e1.substitute(name, value.clone()),
                                            e2.substitute(name, value)),
+            Self::Sub(e1, e2) => Self::sub(e1.substitute(name, value.clone()),
+                                           e2.substitute(name, value)),
             Self::Neg(e) => Self::neg(e.substitute(name, value)),
             Self::Mul(e1, e2) => Self::mul(e1.substitute(name, value.clone()),
                                            e2.substitute(name, value)),
@@ -134,6 +301,782 @@ impl E {
             Self::Func { name:n, arg } => Self::func(n, arg.substitute(name, value)),
             Self::Const(Const::Named(n)) if n == name => value,
             Self::Const(c) => Self::constant(c),
+            Self::Pow(e, n) => Self::pow(e.substitute(name, value), n),
+        }
+    }
+
+    // The argument is rewritten first, so nested applications are replaced as well.
+    fn substitute_func(self, name: &str, replacement: &dyn Fn(Box<Self>) -> Box<Self>)
+        -> Box<Self> {
+        match self {
+            Self::Add(e1, e2) => Self::add(e1.substitute_func(name, replacement),
+                                           e2.substitute_func(name, replacement)),
+            Self::Sub(e1, e2) => Self::sub(e1.substitute_func(name, replacement),
+                                           e2.substitute_func(name, replacement)),
+            Self::Neg(e) => Self::neg(e.substitute_func(name, replacement)),
+            Self::Mul(e1, e2) => Self::mul(e1.substitute_func(name, replacement),
+                                           e2.substitute_func(name, replacement)),
+            Self::Inv(e) => Self::inv(e.substitute_func(name, replacement)),
+            Self::Var(v) => Self::var(v),
+            Self::Func { name: n, arg } => {
+                let arg = arg.substitute_func(name, replacement);
+                if n == name { replacement(arg) } else { Self::func(n, arg) }
+            }
+            Self::Const(c) => Self::constant(c),
+            Self::Pow(e, n) => Self::pow(e.substitute_func(name, replacement), n),
+        }
+    }
+
+    // Bottom-up constant folding, children are simplified before their parent is inspected.
+    fn simplify(self) -> Box<Self> {
+        self.map_children(Self::simplify).fold_node()
+    }
+
+    // substitute followed by simplify, done in the same walk over the tree.
+    fn substitute_and_simplify(self, name: &str, value: Box<Self>) -> Box<Self> {
+        match self {
+            Self::Const(Const::Named(n)) if n == name => value.simplify(),
+            e => e.map_children(|child| child.substitute_and_simplify(name, value.clone()))
+                .fold_node(),
+        }
+    }
+
+    // One step of simplify, for a node whose children are simplified already. The identities
+    // come from IDENTITY_RULES, what they leave is folded again; constants are folded here.
+    fn fold_node(self) -> Box<Self> {
+        if let Some(rule) = IDENTITY_RULES.iter().find(|rule| (rule.matches)(&self)) {
+            return (rule.build)(self).fold_node();
+        }
+        match self {
+            Self::Add(e1, e2) => match (*e1, *e2) {
+                (Self::Const(Const::Numeric(a)), Self::Const(Const::Numeric(b)))
+                    if a.checked_add(b).is_some() => Self::constant(Const::Numeric(a + b)),
+                (e1, e2) => Self::fold_floats(&e1, &e2, |a, b| a + b)
+                    .unwrap_or_else(|| Self::add(Box::new(e1), Box::new(e2))),
+            },
+            Self::Sub(e1, e2) => match (*e1, *e2) {
+                (Self::Const(Const::Numeric(a)), Self::Const(Const::Numeric(b)))
+                    if a.checked_sub(b).is_some() => Self::constant(Const::Numeric(a - b)),
+                (e1, e2) => Self::fold_floats(&e1, &e2, |a, b| a - b)
+                    .unwrap_or_else(|| Self::sub(Box::new(e1), Box::new(e2))),
+            },
+            Self::Mul(e1, e2) => match (*e1, *e2) {
+                (Self::Const(Const::Numeric(a)), Self::Const(Const::Numeric(b)))
+                    if a.checked_mul(b).is_some() => Self::constant(Const::Numeric(a * b)),
+                (e1, e2) => Self::fold_floats(&e1, &e2, |a, b| a * b)
+                    .unwrap_or_else(|| Self::mul(Box::new(e1), Box::new(e2))),
+            },
+            Self::Neg(e) => match *e {
+                Self::Const(Const::Numeric(n)) if n != i64::MIN =>
+                    Self::constant(Const::Numeric(-n)),
+                Self::Const(Const::Float(x)) => Self::constant(Const::Float(-x)),
+                e => Self::neg(Box::new(e)),
+            },
+            Self::Inv(e) => match (e.number_value(), *e) {
+                (Some(x), _) if x != 0.0 => Self::number(1.0 / x),
+                (_, e) => Self::inv(Box::new(e)),
+            },
+            Self::Pow(e, n) => match *e {
+                Self::Const(Const::Numeric(b)) if b.checked_pow(n).is_some() =>
+                    Self::constant(Const::Numeric(b.pow(n))),
+                Self::Const(Const::Float(x)) => Self::number(x.powi(n as i32)),
+                e => Self::pow(Box::new(e), n),
+            },
+            e @ (Self::Func { .. } | Self::Var(_) | Self::Const(_)) => Box::new(e),
+        }
+    }
+
+    // Children are rewritten first, a node is rewritten by the first matching rule until none
+    // matches. Rules that undo each other never finish.
+    fn apply_rules(self, rules: &[RewriteRule]) -> Box<Self> {
+        let e = self.map_children(|child| child.apply_rules(rules));
+        match rules.iter().find(|rule| (rule.matches)(&e)) {
+            Some(rule) => (rule.build)(*e).apply_rules(rules),
+            None => e,
+        }
+    }
+
+    fn map_children(self, f: impl Fn(Self) -> Box<Self>) -> Box<Self> {
+        match self {
+            Self::Add(e1, e2) => Self::add(f(*e1), f(*e2)),
+            Self::Sub(e1, e2) => Self::sub(f(*e1), f(*e2)),
+            Self::Neg(e) => Self::neg(f(*e)),
+            Self::Mul(e1, e2) => Self::mul(f(*e1), f(*e2)),
+            Self::Inv(e) => Self::inv(f(*e)),
+            Self::Func { name, arg } => Self::func(name, f(*arg)),
+            Self::Pow(e, n) => Self::pow(f(*e), n),
+            e @ (Self::Const(_) | Self::Var(_)) => Box::new(e),
+        }
+    }
+
+    // Flattens Add and Mul chains, sorts their operands by to_string and nests them to the right,
+    // so sums and products differing only in operand order become equal.
+    fn normalize(self) -> Box<Self> {
+        match self {
+            Self::Add(..) | Self::Mul(..) => {
+                let is_add = matches!(self, Self::Add(..));
+                let mut operands = Vec::new();
+                self.collect_operands(is_add, &mut operands);
+                let mut operands: Vec<Box<Self>> =
+                    operands.into_iter().map(|e| e.normalize()).collect();
+                operands.sort_by_cached_key(|e| e.to_string());
+                let last = operands.pop().expect("a chain has at least two operands");
+                operands.into_iter().rev().fold(last, |acc, e| {
+                    if is_add { Self::add(e, acc) } else { Self::mul(e, acc) }
+                })
+            }
+            Self::Sub(e1, e2) => Self::sub(e1.normalize(), e2.normalize()),
+            Self::Neg(e) => Self::neg(e.normalize()),
+            Self::Inv(e) => Self::inv(e.normalize()),
+            Self::Func { name, arg } => Self::func(name, arg.normalize()),
+            Self::Pow(e, n) => Self::pow(e.normalize(), n),
+            Self::Var(v) => Self::var(v),
+            Self::Const(c) => Self::constant(c),
+        }
+    }
+
+    // Equal after simplifying and normalizing, so identities and operand order don't matter.
+    fn equivalent(&self, other: &Self) -> bool {
+        self.clone().simplify().normalize() == other.clone().simplify().normalize()
+    }
+
+    fn collect_operands(self, is_add: bool, out: &mut Vec<Self>) {
+        match self {
+            Self::Add(e1, e2) if is_add => {
+                e1.collect_operands(is_add, out);
+                e2.collect_operands(is_add, out);
+            }
+            Self::Mul(e1, e2) if !is_add => {
+                e1.collect_operands(is_add, out);
+                e2.collect_operands(is_add, out);
+            }
+            e => out.push(e),
+        }
+    }
+
+    // Removes the operands of the top-level Add chain matching pred, the rest keep their order.
+    // A Sub is a single operand, an expression that isn't a sum is a sum of one term.
+    fn drop_terms(self, pred: impl Fn(&Self) -> bool) -> Box<Self> {
+        let mut terms = Vec::new();
+        self.collect_operands(true, &mut terms);
+        terms.into_iter()
+            .filter(|e| !pred(e))
+            .map(Box::new)
+            .reduce(Self::add)
+            .unwrap_or_else(|| Self::constant(Const::Numeric(0)))
+    }
+
+    fn rename_func(self, from: &str, to: &str) -> Box<Self> {
+        match self {
+            Self::Add(e1, e2) => Self::add(e1.rename_func(from, to), e2.rename_func(from, to)),
+            Self::Sub(e1, e2) => Self::sub(e1.rename_func(from, to), e2.rename_func(from, to)),
+            Self::Neg(e) => Self::neg(e.rename_func(from, to)),
+            Self::Mul(e1, e2) => Self::mul(e1.rename_func(from, to), e2.rename_func(from, to)),
+            Self::Inv(e) => Self::inv(e.rename_func(from, to)),
+            Self::Func { name, arg } => {
+                let name = if name == from { to.to_string() } else { name };
+                Self::func(name, arg.rename_func(from, to))
+            }
+            Self::Var(v) => Self::var(v),
+            Self::Const(c) => Self::constant(c),
+            Self::Pow(e, n) => Self::pow(e.rename_func(from, to), n),
+        }
+    }
+
+    fn eval(&self, vars: &HashMap<Var, f64>, consts: &HashMap<String, f64>)
+        -> Result<f64, EvalError> {
+        self.eval_with_fns(vars, consts, &HashMap::new())
+    }
+
+    fn eval_with_fns(&self, vars: &HashMap<Var, f64>, consts: &HashMap<String, f64>,
+                     fns: &HashMap<String, fn(f64) -> f64>) -> Result<f64, EvalError> {
+        match self {
+            Self::Add(e1, e2) => Ok(e1.eval_with_fns(vars, consts, fns)?
+                + e2.eval_with_fns(vars, consts, fns)?),
+            Self::Sub(e1, e2) => Ok(e1.eval_with_fns(vars, consts, fns)?
+                - e2.eval_with_fns(vars, consts, fns)?),
+            Self::Neg(e) => Ok(-e.eval_with_fns(vars, consts, fns)?),
+            Self::Mul(e1, e2) => Ok(e1.eval_with_fns(vars, consts, fns)?
+                * e2.eval_with_fns(vars, consts, fns)?),
+            Self::Inv(e) => Ok(1.0 / e.eval_with_fns(vars, consts, fns)?),
+            Self::Const(Const::Numeric(n)) => Ok(*n as f64),
+            Self::Const(Const::Float(x)) => Ok(*x),
+            Self::Const(Const::Named(n)) => consts.get(n).copied()
+                .ok_or_else(|| EvalError::UnboundConst(n.clone())),
+            Self::Var(v) => vars.get(v).copied().ok_or(EvalError::UnboundVar(*v)),
+            Self::Func { name, arg } => {
+                let f = fns.get(name).ok_or_else(|| EvalError::UnknownFunc(name.clone()))?;
+                Ok(f(arg.eval_with_fns(vars, consts, fns)?))
+            }
+            Self::Pow(e, n) => Ok(e.eval_with_fns(vars, consts, fns)?.powi(*n as i32)),
+        }
+    }
+
+    // Like eval of diff, but value and derivative are carried up together, no tree is built.
+    fn eval_derivative(&self, by: Var, vars: &HashMap<Var, f64>, consts: &HashMap<String, f64>)
+        -> Result<f64, EvalError> {
+        Ok(self.eval_dual(by, vars, consts)?.1)
+    }
+
+    fn eval_dual(&self, by: Var, vars: &HashMap<Var, f64>, consts: &HashMap<String, f64>)
+        -> Result<(f64, f64), EvalError> {
+        match self {
+            Self::Add(e1, e2) => {
+                let (f, df) = e1.eval_dual(by, vars, consts)?;
+                let (g, dg) = e2.eval_dual(by, vars, consts)?;
+                Ok((f + g, df + dg))
+            }
+            Self::Sub(e1, e2) => {
+                let (f, df) = e1.eval_dual(by, vars, consts)?;
+                let (g, dg) = e2.eval_dual(by, vars, consts)?;
+                Ok((f - g, df - dg))
+            }
+            Self::Neg(e) => {
+                let (f, df) = e.eval_dual(by, vars, consts)?;
+                Ok((-f, -df))
+            }
+            Self::Mul(e1, e2) => {
+                let (f, df) = e1.eval_dual(by, vars, consts)?;
+                let (g, dg) = e2.eval_dual(by, vars, consts)?;
+                Ok((f * g, df * g + f * dg))
+            }
+            Self::Inv(e) => {
+                let (f, df) = e.eval_dual(by, vars, consts)?;
+                Ok((1.0 / f, -df / (f * f)))
+            }
+            Self::Var(v) => {
+                let value = vars.get(v).copied().ok_or(EvalError::UnboundVar(*v))?;
+                Ok((value, if *v == by { 1.0 } else { 0.0 }))
+            }
+            Self::Const(_) => Ok((self.eval(vars, consts)?, 0.0)),
+            // eval has no functions to call, so neither does this.
+            Self::Func { name, .. } => Err(EvalError::UnknownFunc(name.clone())),
+            Self::Pow(_, 0) => Ok((1.0, 0.0)),
+            Self::Pow(e, n) => {
+                let (f, df) = e.eval_dual(by, vars, consts)?;
+                Ok((f.powi(*n as i32), *n as f64 * f.powi(*n as i32 - 1) * df))
+            }
+        }
+    }
+
+    // Inv factors of products (through Neg too) go to the denominator, sums aren't combined.
+    fn as_fraction(&self) -> (Box<Self>, Box<Self>) {
+        let product = |a: Box<Self>, b: Box<Self>| {
+            if a.is_one() {
+                b
+            } else if b.is_one() {
+                a
+            } else {
+                Self::mul(a, b)
+            }
+        };
+        match self {
+            Self::Mul(e1, e2) => {
+                let (n1, d1) = e1.as_fraction();
+                let (n2, d2) = e2.as_fraction();
+                (product(n1, n2), product(d1, d2))
+            }
+            Self::Inv(e) => {
+                let (n, d) = e.as_fraction();
+                (d, n)
+            }
+            Self::Neg(e) => {
+                let (n, d) = e.as_fraction();
+                (Self::neg(n), d)
+            }
+            e => (Box::new(e.clone()), Self::constant(Const::Numeric(1))),
+        }
+    }
+
+    // Coefficients by power of v, trailing zeros dropped. None if the simplified expression
+    // has another variable, a named constant, or v under Inv or Func.
+    fn poly_coeffs(&self, v: Var) -> Option<Vec<f64>> {
+        let mut coeffs = self.clone().simplify().coeffs_in(v)?;
+        while coeffs.len() > 1 && coeffs.last() == Some(&0.0) {
+            coeffs.pop();
+        }
+        Some(coeffs)
+    }
+
+    // c0 + c1*v + c2*v^2 + ..., zero terms are left out and a coefficient of 1 isn't written.
+    fn from_coeffs(coeffs: &[i64], v: Var) -> Box<Self> {
+        let terms = coeffs.iter().enumerate().filter(|&(_, &c)| c != 0).map(|(k, &c)| {
+            let power = match k {
+                0 => return Self::constant(Const::Numeric(c)),
+                1 => Self::var(v),
+                k => Self::pow(Self::var(v), k as u32),
+            };
+            if c == 1 { power } else { Self::mul(Self::constant(Const::Numeric(c)), power) }
+        });
+        terms.reduce(Self::add).unwrap_or_else(|| Self::constant(Const::Numeric(0)))
+    }
+
+    fn coeffs_in(&self, v: Var) -> Option<Vec<f64>> {
+        let combine = |a: Vec<f64>, b: Vec<f64>, op: fn(f64, f64) -> f64| {
+            (0..a.len().max(b.len()))
+                .map(|i| op(a.get(i).copied().unwrap_or(0.0), b.get(i).copied().unwrap_or(0.0)))
+                .collect()
+        };
+        let product = |a: &[f64], b: &[f64]| {
+            let mut c = vec![0.0; a.len() + b.len() - 1];
+            for (i, x) in a.iter().enumerate() {
+                for (j, y) in b.iter().enumerate() {
+                    c[i + j] += x * y;
+                }
+            }
+            c
+        };
+        match self {
+            Self::Add(e1, e2) => Some(combine(e1.coeffs_in(v)?, e2.coeffs_in(v)?, |a, b| a + b)),
+            Self::Sub(e1, e2) => Some(combine(e1.coeffs_in(v)?, e2.coeffs_in(v)?, |a, b| a - b)),
+            Self::Neg(e) => Some(e.coeffs_in(v)?.into_iter().map(|c| -c).collect()),
+            Self::Mul(e1, e2) => Some(product(&e1.coeffs_in(v)?, &e2.coeffs_in(v)?)),
+            Self::Pow(e, n) => {
+                let base = e.coeffs_in(v)?;
+                Some((0..*n).fold(vec![1.0], |acc, _| product(&acc, &base)))
+            }
+            Self::Var(w) if *w == v => Some(vec![0.0, 1.0]),
+            _ => self.number_value().map(|c| vec![c]),
+        }
+    }
+
+    // Most Mul and Inv nodes on one path from the root to a leaf, Pow isn't counted.
+    fn mul_depth(&self) -> usize {
+        match self {
+            Self::Mul(e1, e2) => 1 + e1.mul_depth().max(e2.mul_depth()),
+            Self::Inv(e) => 1 + e.mul_depth(),
+            Self::Add(e1, e2) | Self::Sub(e1, e2) => e1.mul_depth().max(e2.mul_depth()),
+            Self::Neg(e) | Self::Pow(e, _) | Self::Func { arg: e, .. } => e.mul_depth(),
+            Self::Const(_) | Self::Var(_) => 0,
+        }
+    }
+
+    // Func and Inv aren't polynomial, they are treated as degree 0 like constants.
+    // A degree too large for u32 is capped at u32::MAX.
+    fn degree_in(&self, v: Var) -> u32 {
+        match self {
+            Self::Add(e1, e2) | Self::Sub(e1, e2) => e1.degree_in(v).max(e2.degree_in(v)),
+            Self::Mul(e1, e2) => e1.degree_in(v).saturating_add(e2.degree_in(v)),
+            Self::Neg(e) => e.degree_in(v),
+            Self::Pow(e, n) => e.degree_in(v).saturating_mul(*n),
+            Self::Var(w) if *w == v => 1,
+            Self::Var(_) | Self::Const(_) | Self::Inv(_) | Self::Func { .. } => 0,
+        }
+    }
+
+    fn op_histogram(&self) -> BTreeMap<&'static str, usize> {
+        let mut histogram = BTreeMap::new();
+        self.count_ops(&mut histogram);
+        histogram
+    }
+
+    fn count_ops(&self, histogram: &mut BTreeMap<&'static str, usize>) {
+        let op = match self {
+            Self::Add(..) => "Add",
+            Self::Sub(..) => "Sub",
+            Self::Neg(_) => "Neg",
+            Self::Mul(..) => "Mul",
+            Self::Inv(_) => "Inv",
+            Self::Const(_) => "Const",
+            Self::Func { .. } => "Func",
+            Self::Var(_) => "Var",
+            Self::Pow(..) => "Pow",
+        };
+        *histogram.entry(op).or_insert(0) += 1;
+        for child in self.children() {
+            child.count_ops(histogram);
+        }
+    }
+
+    fn children(&self) -> Vec<&Self> {
+        match self {
+            Self::Add(e1, e2) | Self::Sub(e1, e2) | Self::Mul(e1, e2) => vec![e1, e2],
+            Self::Neg(e) | Self::Inv(e) | Self::Pow(e, _) | Self::Func { arg: e, .. } => vec![e],
+            Self::Const(_) | Self::Var(_) => vec![],
+        }
+    }
+
+    // Nodes on the longest path from the root to a leaf. Walks with its own stack, so it works
+    // on trees too deep for the recursive methods.
+    fn depth(&self) -> usize {
+        let mut deepest = 0;
+        let mut pending = vec![(self, 1)];
+        while let Some((e, level)) = pending.pop() {
+            deepest = deepest.max(level);
+            pending.extend(e.children().into_iter().map(|child| (child, level + 1)));
+        }
+        deepest
+    }
+
+    // Lets only trees of at most `max` levels through, to be checked before recursing over
+    // untrusted input.
+    fn with_max_depth(self, max: usize) -> Result<Box<Self>, TooDeep> {
+        match self.depth() {
+            depth if depth > max => Err(TooDeep {depth, max}),
+            _ => Ok(Box::new(self)),
+        }
+    }
+
+    // Every node of the tree in pre-order, starting with self.
+    fn subexprs(&self) -> Vec<&Self> {
+        let mut nodes = Vec::new();
+        let mut pending = vec![self];
+        while let Some(e) = pending.pop() {
+            nodes.push(e);
+            pending.extend(e.children().into_iter().rev());
+        }
+        nodes
+    }
+
+    // Same sequence Display prints, so joining the tokens back gives `to_string()`.
+    fn tokens(&self) -> Vec<Token> {
+        let mut out = Vec::new();
+        self.push_tokens(&mut out);
+        out
+    }
+
+    fn push_tokens(&self, out: &mut Vec<Token>) {
+        let binary = |e1: &Self, op, e2: &Self, out: &mut Vec<Token>| {
+            out.push(Token::LParen);
+            e1.push_tokens(out);
+            out.push(Token::Op(op));
+            e2.push_tokens(out);
+            out.push(Token::RParen);
+        };
+        match self {
+            Self::Add(e1, e2) => binary(e1, "+", e2, out),
+            Self::Sub(e1, e2) => binary(e1, "-", e2, out),
+            Self::Mul(e1, e2) => binary(e1, "*", e2, out),
+            Self::Pow(e, n) => binary(e, "^", &Self::Const(Const::Numeric(*n as i64)), out),
+            Self::Neg(e) => {
+                out.extend([Token::Op("-"), Token::LParen]);
+                e.push_tokens(out);
+                out.push(Token::RParen);
+            }
+            Self::Inv(e) => {
+                out.extend([Token::Num(1), Token::Op("/"), Token::LParen]);
+                e.push_tokens(out);
+                out.push(Token::RParen);
+            }
+            Self::Func { name, arg } => {
+                out.extend([Token::Ident(name.clone()), Token::LParen]);
+                arg.push_tokens(out);
+                out.push(Token::RParen);
+            }
+            Self::Const(Const::Numeric(n)) => out.push(Token::Num(*n)),
+            Self::Const(Const::Float(x)) => out.push(Token::Float(*x)),
+            Self::Const(Const::Named(n)) => out.push(Token::Ident(n.clone())),
+            Self::Var(v) => out.push(Token::Ident(v.to_string())),
+        }
+    }
+
+    fn to_string_with(&self, opts: &DisplayOptions) -> String {
+        self.render(opts, 0)
+    }
+
+    // `outer` is the precedence the context needs: 1 sums, 2 products, 3 negation,
+    // 4 powers, 5 a power's base.
+    fn render(&self, opts: &DisplayOptions, outer: u8) -> String {
+        // Nodes Display always wraps, and nodes it never wraps.
+        let group = |text: String, prec: u8| {
+            if opts.redundant_parens || prec < outer { format!("({})", text) } else { text }
+        };
+        let tight = |text: String, prec: u8| {
+            if !opts.redundant_parens && prec < outer { format!("({})", text) } else { text }
+        };
+        let operand = |e: &Self| {
+            let text = e.render(opts, 3);
+            if opts.redundant_parens { format!("({})", text) } else { text }
+        };
+        // The right operand of a Sub needs more than its left one, a - (b + c) keeps its parens.
+        let binary = |e1: &Self, op: &str, e2: &Self, prec: u8, right: u8| {
+            group(format!("{}{}{}", e1.render(opts, prec), op, e2.render(opts, right)), prec)
+        };
+        match self {
+            Self::Add(e1, e2) => binary(e1, " + ", e2, 1, 1),
+            Self::Sub(e1, e2) => binary(e1, " - ", e2, 1, 2),
+            Self::Mul(e1, e2) => binary(e1, opts.mul_symbol, e2, 2, 2),
+            Self::Neg(e) => tight(format!("-{}", operand(e)), 3),
+            Self::Inv(e) if opts.inverse_as_power => group(format!("{}^-1", e.render(opts, 5)), 4),
+            Self::Inv(e) => tight(format!("1/{}", operand(e)), 2),
+            Self::Pow(e, n) => group(format!("{}^{}", e.render(opts, 5), n), 4),
+            Self::Func { name, arg } => format!("{}({})", name, arg.render(opts, 0)),
+            Self::Const(Const::Numeric(n)) if *n < 0 => tight(n.to_string(), 3),
+            Self::Const(Const::Float(x)) if *x < 0.0 => tight(x.to_string(), 3),
+            Self::Const(c) => c.to_string(),
+            Self::Var(v) => v.to_string(),
+        }
+    }
+
+    // Graphviz digraph, nodes are numbered in pre-order.
+    fn to_dot(&self) -> String {
+        let mut out = String::from("digraph E {\n");
+        self.dot_node(&mut out, &mut 0);
+        out.push_str("}\n");
+        out
+    }
+
+    fn dot_node(&self, out: &mut String, next_id: &mut usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+        let (label, children): (String, Vec<&Self>) = match self {
+            Self::Add(e1, e2) => ("+".to_string(), vec![e1, e2]),
+            Self::Sub(e1, e2) => ("-".to_string(), vec![e1, e2]),
+            Self::Neg(e) => ("neg".to_string(), vec![e]),
+            Self::Mul(e1, e2) => ("*".to_string(), vec![e1, e2]),
+            Self::Inv(e) => ("1/".to_string(), vec![e]),
+            Self::Const(c) => (c.to_string(), vec![]),
+            Self::Func { name, arg } => (name.clone(), vec![arg]),
+            Self::Var(v) => (v.to_string(), vec![]),
+            Self::Pow(e, n) => (format!("^{}", n), vec![e]),
+        };
+        out.push_str(&format!("  n{} [label={:?}];\n", id, label));
+        for child in children {
+            let child_id = child.dot_node(out, next_id);
+            out.push_str(&format!("  n{} -> n{};\n", id, child_id));
+        }
+        id
+    }
+
+    // Integral values stay Numeric, anything else becomes a Float.
+    fn number(value: f64) -> Box<Self> {
+        if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+            Self::constant(Const::Numeric(value as i64))
+        } else {
+            Self::constant(Const::Float(value))
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        matches!(self, Self::Const(Const::Numeric(0)))
+    }
+
+    fn is_one(&self) -> bool {
+        matches!(self, Self::Const(Const::Numeric(1)))
+    }
+
+    // Integer value of a tree built only from Numeric constants, None on anything else
+    // (variables, named or float constants, Inv, Func) or on overflow.
+    fn try_eval_const(&self) -> Option<i64> {
+        match self {
+            Self::Const(Const::Numeric(n)) => Some(*n),
+            Self::Add(e1, e2) => e1.try_eval_const()?.checked_add(e2.try_eval_const()?),
+            Self::Sub(e1, e2) => e1.try_eval_const()?.checked_sub(e2.try_eval_const()?),
+            Self::Mul(e1, e2) => e1.try_eval_const()?.checked_mul(e2.try_eval_const()?),
+            Self::Neg(e) => e.try_eval_const()?.checked_neg(),
+            Self::Pow(e, n) => e.try_eval_const()?.checked_pow(*n),
+            _ => None,
+        }
+    }
+
+    // Finds an Inv of something that is zero whatever the bindings: an integer constant
+    // expression equal to 0 or a difference of two equivalent expressions.
+    fn check_div_by_zero(&self) -> Result<(), String> {
+        let is_zero = |e: &Self| match e {
+            Self::Sub(e1, e2) if e1.equivalent(e2) => true,
+            e => e.try_eval_const() == Some(0),
+        };
+        match self.subexprs().into_iter().find(|e| matches!(e, Self::Inv(d) if is_zero(d))) {
+            Some(e) => Err(format!("Division by zero in {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    fn number_value(&self) -> Option<f64> {
+        match self {
+            Self::Const(Const::Numeric(n)) => Some(*n as f64),
+            Self::Const(Const::Float(x)) => Some(*x),
+            _ => None,
+        }
+    }
+
+    // Folds two constants if at least one is a Float, integer pairs are folded exactly.
+    fn fold_floats(e1: &Self, e2: &Self, op: fn(f64, f64) -> f64) -> Option<Box<Self>> {
+        let is_float = |e: &Self| matches!(e, Self::Const(Const::Float(_)));
+        if !is_float(e1) && !is_float(e2) {
+            return None;
+        }
+        Some(Self::number(op(e1.number_value()?, e2.number_value()?)))
+    }
+
+    // f(a) + f'(a) * (at - a), None if f or f' can't be evaluated at the point
+    // (other variables, named constants, functions).
+    fn linearize(self, at: Var, point: f64) -> Option<Box<Self>> {
+        let vars = HashMap::from([(at, point)]);
+        let consts = HashMap::new();
+        let value = self.eval(&vars, &consts).ok()?;
+        let slope = self.diff(at).eval(&vars, &consts).ok()?;
+        Some(Self::add(
+            Self::number(value),
+            Self::mul(Self::number(slope), Self::sub(Self::var(at), Self::number(point))),
+        ))
+    }
+}
+
+impl ERc {
+    fn diff(&self, by: Var) -> Rc<Self> {
+        match self {
+            Self::Add(e1, e2) => Rc::new(Self::Add(e1.diff(by), e2.diff(by))),
+            Self::Sub(e1, e2) => Rc::new(Self::Sub(e1.diff(by), e2.diff(by))),
+            Self::Neg(e) => Rc::new(Self::Neg(e.diff(by))),
+            Self::Mul(f, g) => Rc::new(Self::Add(
+                Rc::new(Self::Mul(f.diff(by), Rc::clone(g))),
+                Rc::new(Self::Mul(Rc::clone(f), g.diff(by))),
+            )),
+            Self::Inv(f) => {
+                let f_squared = Rc::new(Self::Mul(Rc::clone(f), Rc::clone(f)));
+                Rc::new(Self::Mul(Rc::new(Self::Neg(Rc::new(Self::Inv(f_squared)))), f.diff(by)))
+            }
+            Self::Const(_) => Rc::new(Self::Const(Const::Numeric(0))),
+            Self::Var(v) => Rc::new(Self::Const(Const::Numeric(if *v == by { 1 } else { 0 }))),
+            Self::Func { name, arg } => {
+                let f_diff = Rc::new(Self::Func {
+                    name: format!("{}_{}", name, by),
+                    arg: Rc::clone(arg),
+                });
+                Rc::new(Self::Mul(f_diff, arg.diff(by)))
+            }
+            Self::Pow(_, 0) => Rc::new(Self::Const(Const::Numeric(0))),
+            Self::Pow(e, n) => {
+                let n_const = Rc::new(Self::Const(Const::Numeric(*n as i64)));
+                let lowered = Rc::new(Self::Pow(Rc::clone(e), n - 1));
+                Rc::new(Self::Mul(Rc::new(Self::Mul(n_const, lowered)), e.diff(by)))
+            }
+        }
+    }
+}
+
+impl From<&E> for ERc {
+    fn from(e: &E) -> Self {
+        match e {
+            E::Add(e1, e2) => Self::Add(Rc::new(e1.as_ref().into()), Rc::new(e2.as_ref().into())),
+            E::Sub(e1, e2) => Self::Sub(Rc::new(e1.as_ref().into()), Rc::new(e2.as_ref().into())),
+            E::Neg(e) => Self::Neg(Rc::new(e.as_ref().into())),
+            E::Mul(e1, e2) => Self::Mul(Rc::new(e1.as_ref().into()), Rc::new(e2.as_ref().into())),
+            E::Inv(e) => Self::Inv(Rc::new(e.as_ref().into())),
+            E::Const(c) => Self::Const(c.clone()),
+            E::Func { name, arg } => Self::Func {
+                name: name.clone(),
+                arg: Rc::new(arg.as_ref().into()),
+            },
+            E::Var(v) => Self::Var(*v),
+            E::Pow(e, n) => Self::Pow(Rc::new(e.as_ref().into()), *n),
+        }
+    }
+}
+
+// Key of an interned node. Children are already interned, so they are identified by address.
+#[derive(PartialEq, Eq, Hash)]
+enum NodeKey {
+    Add(*const ERc, *const ERc),
+    Sub(*const ERc, *const ERc),
+    Neg(*const ERc),
+    Mul(*const ERc, *const ERc),
+    Inv(*const ERc),
+    Numeric(i64),
+    Named(String),
+    Float(u64),
+    Func(String, *const ERc),
+    Var(Var),
+    Pow(*const ERc, u32),
+}
+
+// Hash-consing pool: structurally identical subtrees are built once and shared.
+#[derive(Default)]
+struct ExprPool {
+    nodes: HashMap<NodeKey, Rc<ERc>>,
+}
+
+impl ExprPool {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn intern_node(&mut self, key: NodeKey, node: impl FnOnce() -> ERc) -> Rc<ERc> {
+        Rc::clone(self.nodes.entry(key).or_insert_with(|| Rc::new(node())))
+    }
+
+    fn build_add(&mut self, arg1: Rc<ERc>, arg2: Rc<ERc>) -> Rc<ERc> {
+        let key = NodeKey::Add(Rc::as_ptr(&arg1), Rc::as_ptr(&arg2));
+        self.intern_node(key, || ERc::Add(arg1, arg2))
+    }
+
+    fn build_sub(&mut self, arg1: Rc<ERc>, arg2: Rc<ERc>) -> Rc<ERc> {
+        let key = NodeKey::Sub(Rc::as_ptr(&arg1), Rc::as_ptr(&arg2));
+        self.intern_node(key, || ERc::Sub(arg1, arg2))
+    }
+
+    fn build_neg(&mut self, arg1: Rc<ERc>) -> Rc<ERc> {
+        self.intern_node(NodeKey::Neg(Rc::as_ptr(&arg1)), || ERc::Neg(arg1))
+    }
+
+    fn build_mul(&mut self, arg1: Rc<ERc>, arg2: Rc<ERc>) -> Rc<ERc> {
+        let key = NodeKey::Mul(Rc::as_ptr(&arg1), Rc::as_ptr(&arg2));
+        self.intern_node(key, || ERc::Mul(arg1, arg2))
+    }
+
+    fn build_inv(&mut self, arg1: Rc<ERc>) -> Rc<ERc> {
+        self.intern_node(NodeKey::Inv(Rc::as_ptr(&arg1)), || ERc::Inv(arg1))
+    }
+
+    fn build_constant(&mut self, c: Const) -> Rc<ERc> {
+        let key = match &c {
+            Const::Numeric(n) => NodeKey::Numeric(*n),
+            Const::Named(name) => NodeKey::Named(name.clone()),
+            Const::Float(x) => NodeKey::Float(x.to_bits()),
+        };
+        self.intern_node(key, || ERc::Const(c))
+    }
+
+    fn build_func(&mut self, name: String, arg: Rc<ERc>) -> Rc<ERc> {
+        let key = NodeKey::Func(name.clone(), Rc::as_ptr(&arg));
+        self.intern_node(key, || ERc::Func { name, arg })
+    }
+
+    fn build_var(&mut self, v: Var) -> Rc<ERc> {
+        self.intern_node(NodeKey::Var(v), || ERc::Var(v))
+    }
+
+    fn build_pow(&mut self, base: Rc<ERc>, exp: u32) -> Rc<ERc> {
+        self.intern_node(NodeKey::Pow(Rc::as_ptr(&base), exp), || ERc::Pow(base, exp))
+    }
+
+    fn intern_expr(&mut self, e: &E) -> Rc<ERc> {
+        match e {
+            E::Add(e1, e2) => {
+                let (a, b) = (self.intern_expr(e1), self.intern_expr(e2));
+                self.build_add(a, b)
+            }
+            E::Sub(e1, e2) => {
+                let (a, b) = (self.intern_expr(e1), self.intern_expr(e2));
+                self.build_sub(a, b)
+            }
+            E::Neg(e) => {
+                let a = self.intern_expr(e);
+                self.build_neg(a)
+            }
+            E::Mul(e1, e2) => {
+                let (a, b) = (self.intern_expr(e1), self.intern_expr(e2));
+                self.build_mul(a, b)
+            }
+            E::Inv(e) => {
+                let a = self.intern_expr(e);
+                self.build_inv(a)
+            }
+            E::Const(c) => self.build_constant(c.clone()),
+            E::Func { name, arg } => {
+                let a = self.intern_expr(arg);
+                self.build_func(name.clone(), a)
+            }
+            E::Var(v) => self.build_var(*v),
+            E::Pow(e, n) => {
+                let a = self.intern_expr(e);
+                self.build_pow(a, *n)
+            }
         }
     }
 }
@@ -153,6 +1096,7 @@ impl fmt::Display for Const {
         match self {
             Const::Numeric(n) => write!(f, "{}", n),
             Const::Named(n) => write!(f, "{}", n),
+            Const::Float(x) => write!(f, "{}", x),
         }
     }
 }
@@ -161,12 +1105,30 @@ impl fmt::Display for E {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             E::Add(e1, e2) => write!(f, "({} + {})", e1, e2),
+            E::Sub(e1, e2) => write!(f, "({} - {})", e1, e2),
             E::Neg(e) => write!(f, "-({})", e),
             E::Mul(e1, e2) => write!(f, "({} * {})", e1, e2),
             E::Inv(e) => write!(f, "1/({})", e),
             E::Const(c) => write!(f, "{}", c),
             E::Var(v) => write!(f, "{}", v),
             E::Func { name, arg } => write!(f, "{}({})", name, arg),
+            E::Pow(e, n) => write!(f, "({}^{})", e, n),
+        }
+    }
+}
+
+impl fmt::Display for ERc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ERc::Add(e1, e2) => write!(f, "({} + {})", e1, e2),
+            ERc::Sub(e1, e2) => write!(f, "({} - {})", e1, e2),
+            ERc::Neg(e) => write!(f, "-({})", e),
+            ERc::Mul(e1, e2) => write!(f, "({} * {})", e1, e2),
+            ERc::Inv(e) => write!(f, "1/({})", e),
+            ERc::Const(c) => write!(f, "{}", c),
+            ERc::Var(v) => write!(f, "{}", v),
+            ERc::Func { name, arg } => write!(f, "{}({})", name, arg),
+            ERc::Pow(e, n) => write!(f, "({}^{})", e, n),
         }
     }
 }
@@ -199,15 +1161,95 @@ fn main() {
     // Derivative
     let df_dx = f.clone().diff(Var::X);
     println!("Derivative expression of X: {}", df_dx);
+    println!("Operations in the derivative: {:?}", df_dx.op_histogram());
+    println!("Variables in the derivative: {}",
+             df_dx.subexprs().iter().filter(|e| matches!(e, E::Var(_))).count());
 
     // Substituting value
     let a_value = E::constant(Const::Numeric(3));
-    let df_dx_substituted = df_dx.substitute("a", a_value);
+    let df_dx_substituted = df_dx.clone().substitute("a", a_value);
     println!("Derivative with substitution: {}", df_dx_substituted);
+    println!("Derivative with a = 0: {}",
+             df_dx.clone().substitute_and_simplify("a", E::constant(Const::Numeric(0))));
+    let df_dx_renamed = df_dx_substituted.rename_func("sin_X", "cos");
+    println!("Derivative with renamed function: {}", df_dx_renamed);
+    println!("Derivative after identity rules: {}",
+             df_dx_renamed.clone().apply_rules(&IDENTITY_RULES));
+    println!("Simplified derivative: {}", df_dx_renamed.simplify());
+
+    match E::try_func("sin X", E::var(Var::X)) {
+        Ok(e) => println!("Created {}", e),
+        Err(e) => println!("Rejected function name: {:?}", e),
+    }
+
+    let squared = E::func(String::from("sq"), E::var(Var::Y))
+        .substitute_func("sq", &|arg| E::mul(arg.clone(), arg));
+    println!("Expanded sq(Y): {}", squared);
+
+    let x_a = E::mul(E::var(Var::X), E::constant(Const::Named(String::from("a"))));
+    let reordered = E::add(E::var(Var::Y), E::func(String::from("sin"), x_a));
+    println!("{} and {} equal after normalizing: {}", f, reordered,
+             f.clone().normalize() == reordered.clone().normalize());
+    println!("{} equivalent to {}: {}", reordered, f, reordered.equivalent(&f));
+    print!("Expression tree of f:\n{}", f.to_dot());
+    println!("f without its Y term: {}", f.clone().drop_terms(|e| e.degree_in(Var::Y) > 0));
 
     // Sample usage of previously not used functions
     let g = E::add(E::var(Var::Z), E::constant(Const::Numeric(100)));
     println!("Expression g = {}", g);
+
+    // Derivative on shared subtrees
+    let f_rc = ERc::from(f.as_ref());
+    println!("Shared derivative of X: {}", f_rc.diff(Var::X));
+
+    // Hash-consing identical subtrees
+    let mut pool = ExprPool::new();
+    let pooled = pool.intern_expr(&df_dx);
+    println!("Pooled derivative {} uses {} distinct nodes", pooled, pool.len());
+
+    // Polynomial degree
+    let p = E::add(E::pow(E::var(Var::X), 3), E::mul(E::var(Var::X), E::var(Var::Y)));
+    println!("Degree of {} in X: {}, in Y: {}", p, p.degree_in(Var::X), p.degree_in(Var::Y));
+    println!("Multiplicative depth of {}: {}", p, p.mul_depth());
+    match p.clone().with_max_depth(3) {
+        Ok(e) => println!("{} is at most 3 levels deep", e),
+        Err(e) => println!("{} is too deep: {:?}", p, e),
+    }
+    let n = |v| E::constant(Const::Numeric(v));
+    let folded = E::add(n(2), E::mul(n(3), n(4)));
+    println!("Constant value of {}: {:?}", folded, folded.try_eval_const());
+    println!("Tokens of {}: {:?}", folded, folded.tokens());
+    let compact = DisplayOptions {mul_symbol: "·", redundant_parens: false,
+                                  inverse_as_power: true};
+    println!("Compact form of {}: {}", f, f.to_string_with(&compact));
+    let singular = E::add(E::var(Var::Y), E::inv(E::sub(folded.clone(), n(14))));
+    println!("Checking {}: {:?}", singular, singular.check_div_by_zero());
+    let q = E::pow(E::add(E::var(Var::X), E::constant(Const::Numeric(1))), 2);
+    println!("Coefficients of {} in X: {:?}", q, q.poly_coeffs(Var::X));
+    println!("Polynomial from [1, 2, 1]: {}", E::from_coeffs(&[1, 2, 1], Var::X));
+    println!("Derivative of {} by X: {}", p, p.clone().diff(Var::X));
+    println!("Second derivative of {} by X: {}", p, p.clone().diff_n(Var::X, 2));
+
+    let ratio = E::mul(E::var(Var::X), E::inv(E::add(E::var(Var::Y), E::var(Var::Z))));
+    let (num, den) = ratio.as_fraction();
+    println!("{} has numerator {} and denominator {}", ratio, num, den);
+
+    // Linear approximation
+    let square = E::mul(E::var(Var::X), E::var(Var::X));
+    println!("{} near X = 3: {:?}", square, square.clone().linearize(Var::X, 3.0)
+        .map(|l| l.to_string()));
+
+    // Evaluation
+    let vars = HashMap::from([(Var::X, 0.5), (Var::Y, 2.0), (Var::Z, 1.0)]);
+    let consts = HashMap::from([(String::from("a"), 3.0)]);
+    let fns: HashMap<String, fn(f64) -> f64> = HashMap::from([
+        (String::from("sin"), f64::sin as fn(f64) -> f64),
+        (String::from("sin_X"), f64::cos as fn(f64) -> f64),
+    ]);
+    println!("g at Z = 1: {:?}", g.eval(&vars, &consts));
+    println!("dp/dX at X = 0.5, Y = 2: {:?}", p.eval_derivative(Var::X, &vars, &consts));
+    println!("f at X = 0.5, Y = 2: {:?}", f.eval_with_fns(&vars, &consts, &fns));
+    println!("df/dX at X = 0.5: {:?}", df_dx.eval_with_fns(&vars, &consts, &fns));
 }
 
 #[cfg(test)]
@@ -222,6 +1264,39 @@ mod tests {
         assert_eq!(c_name.to_string(), "a");
     }
 
+    #[test]
+    fn test_float_to_string() {
+        assert_eq!(Const::Float(3.5).to_string(), "3.5");
+        assert_eq!(Const::Float(0.125).to_string(), "0.125");
+        assert_eq!(Const::Float(-2.0).to_string(), "-2");
+        assert_eq!(E::neg(E::constant(Const::Float(1.5))).to_string(), "-(1.5)");
+    }
+
+    #[test]
+    fn test_float_diff_and_eval() {
+        let expr = E::mul(E::constant(Const::Float(2.5)), E::var(Var::X));
+        let vars = HashMap::from([(Var::X, 4.0)]);
+        assert_eq!(expr.eval(&vars, &HashMap::new()), Ok(10.0));
+        assert_eq!(expr.diff(Var::X).simplify().to_string(), "2.5");
+        assert_eq!(E::constant(Const::Float(0.5)).diff(Var::X).to_string(), "0");
+    }
+
+    #[test]
+    fn test_simplify_folds_floats() {
+        let half = E::inv(E::constant(Const::Numeric(2))).simplify();
+        assert!(matches!(*half, E::Const(Const::Float(x)) if x == 0.5));
+        let one = E::inv(E::constant(Const::Numeric(1))).simplify();
+        assert!(matches!(*one, E::Const(Const::Numeric(1))));
+        let two = E::inv(E::inv(E::constant(Const::Numeric(2)))).simplify();
+        assert!(matches!(*two, E::Const(Const::Numeric(2))));
+        let sum = E::add(E::constant(Const::Float(0.5)), E::constant(Const::Numeric(1)));
+        assert_eq!(sum.simplify().to_string(), "1.5");
+        let product = E::mul(E::pow(E::constant(Const::Float(0.5)), 2),
+                             E::constant(Const::Numeric(8)));
+        assert!(matches!(*product.simplify(), E::Const(Const::Numeric(2))));
+        assert_eq!(E::inv(E::constant(Const::Numeric(0))).simplify().to_string(), "1/(0)");
+    }
+
     #[test]
     fn test_var_to_string() {
         assert_eq!(Var::X.to_string(), "X");
@@ -267,6 +1342,20 @@ mod tests {
         assert_eq!(expr.to_string(), "f(X)");
     }
 
+    #[test]
+    fn test_try_func_valid_name() {
+        assert_eq!(E::try_func("sin_X", E::var(Var::X)),
+                   Ok(E::func(String::from("sin_X"), E::var(Var::X))));
+        assert!(E::try_func("_f2", E::var(Var::X)).is_ok());
+    }
+
+    #[test]
+    fn test_try_func_bad_names() {
+        for name in ["", "a b", "1f", "f(x)"] {
+            assert_eq!(E::try_func(name, E::var(Var::X)), Err(BadName(name.to_string())));
+        }
+    }
+
     #[test]
     fn test_expr_to_string_complex() {
         let expr1 = E::add(E::constant(Const::Numeric(2)), E::var(Var::X));
@@ -330,6 +1419,406 @@ mod tests {
         assert_eq!(substituted.to_string(), "(3 * f(3))");
     }
 
+    #[test]
+    fn test_substitute_and_simplify() {
+        let a = || E::constant(Const::Named("a".into()));
+        let zero = E::constant(Const::Numeric(0));
+        let expr = E::mul(a(), E::var(Var::X));
+        assert_eq!(expr.substitute_and_simplify("a", zero.clone()), zero);
+
+        let expr = E::add(E::mul(a(), E::var(Var::X)), E::pow(E::add(a(), E::var(Var::Y)), 1));
+        assert_eq!(expr.clone().substitute_and_simplify("a", zero.clone()).to_string(), "Y");
+        assert_eq!(expr.clone().substitute_and_simplify("a", zero.clone()),
+                   expr.substitute("a", zero).simplify());
+    }
+
+    #[test]
+    fn test_substitute_func_expands_square() {
+        let expr = E::func("sq".into(), E::add(E::var(Var::X), E::constant(Const::Numeric(1))));
+        let expanded = expr.substitute_func("sq", &|arg| E::mul(arg.clone(), arg));
+        assert_eq!(expanded.to_string(), "((X + 1) * (X + 1))");
+    }
+
+    #[test]
+    fn test_substitute_func_nested_and_other_names() {
+        let expr = E::add(
+            E::func("sq".into(), E::func("sq".into(), E::var(Var::X))),
+            E::func("sin".into(), E::func("sq".into(), E::var(Var::Y))),
+        );
+        let expanded = expr.substitute_func("sq", &|arg| E::pow(arg, 2));
+        assert_eq!(expanded.to_string(), "(((X^2)^2) + sin((Y^2)))");
+    }
+
+    #[test]
+    fn test_to_dot_product() {
+        let dot = E::mul(E::var(Var::X), E::var(Var::Y)).to_dot();
+        let expected = ["digraph E {", "  n0 [label=\"*\"];", "  n1 [label=\"X\"];", "  n0 -> n1;",
+                        "  n2 [label=\"Y\"];", "  n0 -> n2;", "}", ""];
+        assert_eq!(dot, expected.join("\n"));
+    }
+
+    #[test]
+    fn test_to_dot_unique_ids() {
+        let x = E::var(Var::X);
+        let expr = E::neg(E::add(E::func("sin".into(), x.clone()), E::pow(x, 2)));
+        let dot = expr.to_dot();
+        for line in ["n0 [label=\"neg\"]", "n1 [label=\"+\"]", "n2 [label=\"sin\"]",
+                     "n3 [label=\"X\"]", "n4 [label=\"^2\"]", "n5 [label=\"X\"]",
+                     "n0 -> n1;", "n1 -> n2;", "n2 -> n3;", "n1 -> n4;", "n4 -> n5;"] {
+            assert!(dot.contains(line), "missing {} in {}", line, dot);
+        }
+        assert_eq!(dot.matches("->").count(), 5);
+    }
+
+    #[test]
+    fn test_normalize_reordered_sums() {
+        let (x, y, z) = (E::var(Var::X), E::var(Var::Y), E::var(Var::Z));
+        let left = E::add(E::add(x.clone(), y.clone()), z.clone());
+        let right = E::add(z, E::add(y, x));
+        assert_ne!(left, right);
+        let (left, right) = (left.normalize(), right.normalize());
+        assert_eq!(left, right);
+        assert_eq!(left.to_string(), "(X + (Y + Z))");
+    }
+
+    #[test]
+    fn test_normalize_nested_products() {
+        let two = E::constant(Const::Numeric(2));
+        let left = E::neg(E::mul(E::add(E::var(Var::Y), two.clone()),
+                                 E::mul(E::var(Var::X), E::var(Var::Z))));
+        let right = E::neg(E::mul(E::var(Var::Z),
+                                  E::mul(E::add(two, E::var(Var::Y)), E::var(Var::X))));
+        assert_eq!(left.clone().normalize(), right.normalize());
+        assert_eq!(left.normalize().to_string(), "-(((2 + Y) * (X * Z)))");
+        let diff = E::sub(E::var(Var::Y), E::var(Var::X));
+        assert_eq!(diff.clone().normalize(), diff);
+    }
+
+    #[test]
+    fn test_equivalent_pairs() {
+        let x = || E::var(Var::X);
+        let y = || E::var(Var::Y);
+        let num = |n| E::constant(Const::Numeric(n));
+        assert!(E::add(x(), num(0)).equivalent(&x()));
+        assert!(E::add(x(), y()).equivalent(&E::add(y(), x())));
+        assert!(E::mul(E::add(x(), y()), num(1)).equivalent(&E::add(y(), E::mul(num(1), x()))));
+        assert!(E::neg(E::neg(x())).equivalent(&E::mul(x(), E::pow(y(), 0))));
+        assert!(E::mul(num(2), num(3)).equivalent(&num(6)));
+    }
+
+    #[test]
+    fn test_not_equivalent_pairs() {
+        let x = || E::var(Var::X);
+        let y = || E::var(Var::Y);
+        assert!(!E::sub(x(), y()).equivalent(&E::sub(y(), x())));
+        assert!(!E::add(x(), x()).equivalent(&x()));
+        assert!(!E::mul(x(), y()).equivalent(&E::add(x(), y())));
+    }
+
+    #[test]
+    fn test_drop_terms_numeric_constants() {
+        let is_number = |e: &E| matches!(e, E::Const(Const::Numeric(_)));
+        let sum = E::add(E::add(E::constant(Const::Numeric(2)), E::var(Var::X)),
+                         E::add(E::mul(E::var(Var::Y), E::constant(Const::Numeric(3))),
+                                E::constant(Const::Numeric(5))));
+        let expected = E::add(E::var(Var::X),
+                              E::mul(E::var(Var::Y), E::constant(Const::Numeric(3))));
+        assert_eq!(sum.drop_terms(is_number), expected);
+    }
+
+    #[test]
+    fn test_drop_terms_all_or_none() {
+        let sum = E::add(E::constant(Const::Numeric(1)), E::constant(Const::Numeric(2)));
+        assert_eq!(sum.drop_terms(|_| true), E::constant(Const::Numeric(0)));
+        let diff = E::sub(E::var(Var::X), E::var(Var::Y));
+        assert_eq!(diff.clone().drop_terms(|e| *e == E::Var(Var::Y)), diff);
+    }
+
+    #[test]
+    fn test_rename_func_nested() {
+        let expr = E::add(
+            E::func("sin".into(), E::mul(E::var(Var::X), E::func("sin".into(), E::var(Var::Y)))),
+            E::neg(E::func("sinh".into(), E::var(Var::X))),
+        );
+        let renamed = expr.rename_func("sin", "cos");
+        assert_eq!(renamed.to_string(), "(cos((X * cos(Y))) + -(sinh(X)))");
+    }
+
+    #[test]
+    fn test_simplify_folds_constants() {
+        let expr = E::add(
+            E::mul(E::constant(Const::Numeric(2)), E::constant(Const::Numeric(3))),
+            E::neg(E::constant(Const::Numeric(4))),
+        );
+        assert_eq!(expr.simplify().to_string(), "2");
+        let identities = E::add(E::mul(E::constant(Const::Numeric(1)), E::var(Var::X)),
+                                E::constant(Const::Numeric(0)));
+        assert_eq!(identities.simplify().to_string(), "X");
+    }
+
+    #[test]
+    fn test_is_zero_and_is_one() {
+        assert!(E::constant(Const::Numeric(0)).is_zero());
+        assert!(E::constant(Const::Numeric(1)).is_one());
+        assert!(!E::constant(Const::Numeric(1)).is_zero());
+        assert!(!E::constant(Const::Float(1.0)).is_one());
+        assert!(!E::var(Var::X).is_zero());
+    }
+
+    #[test]
+    fn test_apply_identity_rules() {
+        let x = || E::var(Var::X);
+        let num = |n| E::constant(Const::Numeric(n));
+        let e = E::neg(E::neg(E::add(E::mul(num(1), x()), E::sub(num(0), E::pow(x(), 1)))));
+        assert_eq!(e.apply_rules(&IDENTITY_RULES), E::add(x(), E::neg(x())));
+        let e = E::inv(E::inv(E::mul(E::func(String::from("f"), x()), E::pow(x(), 0))));
+        assert_eq!(e.apply_rules(&IDENTITY_RULES), E::func(String::from("f"), x()));
+        assert_eq!(E::mul(x(), E::sub(x(), num(0))).apply_rules(&[]),
+                   E::mul(x(), E::sub(x(), num(0))));
+    }
+
+    #[test]
+    fn test_apply_custom_rule() {
+        let double = RewriteRule {
+            matches: |e| matches!(e, E::Add(a, b) if a == b),
+            build: |e| match e {
+                E::Add(a, _) => E::mul(E::constant(Const::Numeric(2)), a),
+                e => Box::new(e),
+            },
+        };
+        let x = || E::var(Var::X);
+        let mut rules = IDENTITY_RULES.to_vec();
+        rules.push(double);
+        // X + 0 and X * 1 only become equal once the identities have fired
+        let e = E::add(E::add(x(), E::constant(Const::Numeric(0))),
+                       E::mul(x(), E::constant(Const::Numeric(1))));
+        assert_eq!(e.apply_rules(&rules), E::mul(E::constant(Const::Numeric(2)), x()));
+    }
+
+    #[test]
+    fn test_simplify_identities_on_either_side() {
+        let x = || E::var(Var::X);
+        let one = || E::constant(Const::Numeric(1));
+        let zero = || E::constant(Const::Numeric(0));
+        assert_eq!(E::mul(x(), one()).simplify(), x());
+        assert_eq!(E::mul(one(), x()).simplify(), x());
+        assert_eq!(E::add(x(), zero()).simplify(), x());
+        assert_eq!(E::add(zero(), x()).simplify(), x());
+        assert_eq!(E::sub(zero(), x()).simplify(), E::neg(x()));
+        assert_eq!(E::mul(x(), zero()).simplify(), zero());
+    }
+
+    #[test]
+    fn test_simplify_mul_by_folded_zero() {
+        // X * (1 + -(1))
+        let zero = E::add(E::constant(Const::Numeric(1)), E::neg(E::constant(Const::Numeric(1))));
+        let expr = E::mul(E::var(Var::X), zero.clone());
+        assert_eq!(expr.simplify().to_string(), "0");
+        let flipped = E::mul(zero.clone(), E::func("f".into(), E::var(Var::Y)));
+        assert_eq!(flipped.simplify().to_string(), "0");
+        let deep = E::add(E::func("f".into(), E::mul(E::var(Var::X), zero)), E::var(Var::Y));
+        assert_eq!(deep.simplify().to_string(), "(f(0) + Y)");
+    }
+
+    #[test]
+    fn test_simplify_derivative() {
+        let expr = E::mul(E::var(Var::X), E::var(Var::Y));
+        assert_eq!(expr.diff(Var::X).simplify().to_string(), "Y");
+        let cube = E::pow(E::var(Var::X), 3);
+        assert_eq!(cube.diff(Var::X).simplify().to_string(), "(3 * (X^2))");
+    }
+
+    #[test]
+    fn test_builder_sub() {
+        let expr = E::sub(E::var(Var::X), E::constant(Const::Numeric(2)));
+        assert_eq!(expr.to_string(), "(X - 2)");
+        assert_eq!(expr.arg_count(), 2);
+        assert_eq!(expr.clone().diff(Var::X).to_string(), "(1 - 0)");
+        assert_eq!(expr.diff(Var::X).simplify().to_string(), "1");
+    }
+
+    #[test]
+    fn test_simplify_nested_double_neg_and_inv() {
+        let expr = E::add(E::var(Var::X), E::neg(E::neg(E::var(Var::Y))));
+        assert_eq!(expr.simplify().to_string(), "(X + Y)");
+
+        let deep = E::add(
+            E::mul(E::neg(E::neg(E::neg(E::var(Var::X)))), E::inv(E::inv(E::var(Var::Y)))),
+            E::func("f".into(), E::neg(E::neg(E::inv(E::inv(E::inv(E::var(Var::Z))))))),
+        );
+        assert_eq!(deep.simplify().to_string(), "((-(X) * Y) + f(1/(Z)))");
+
+        let zero = || E::constant(Const::Numeric(0));
+        assert_eq!(E::sub(zero(), E::neg(E::var(Var::X))).simplify(), E::var(Var::X));
+        assert_eq!(E::sub(zero(), E::constant(Const::Float(2.0))).simplify(),
+                   E::constant(Const::Float(-2.0)));
+    }
+
+    #[test]
+    fn test_simplify_sub() {
+        let one_minus_one = E::sub(E::constant(Const::Numeric(1)), E::constant(Const::Numeric(1)));
+        assert_eq!(E::mul(E::var(Var::X), one_minus_one).simplify().to_string(), "0");
+        let zero_minus_x = E::sub(E::constant(Const::Numeric(0)), E::var(Var::X));
+        assert_eq!(zero_minus_x.simplify().to_string(), "-(X)");
+    }
+
+    #[test]
+    fn test_poly_coeffs_quadratic() {
+        let x = || E::var(Var::X);
+        let num = |n| E::constant(Const::Numeric(n));
+        let p = E::add(E::add(E::mul(x(), x()), E::mul(num(2), x())), num(1));
+        assert_eq!(p.poly_coeffs(Var::X), Some(vec![1.0, 2.0, 1.0]));
+        // (X - 1)^3 = X^3 - 3X^2 + 3X - 1
+        let cube = E::pow(E::sub(x(), num(1)), 3);
+        assert_eq!(cube.poly_coeffs(Var::X), Some(vec![-1.0, 3.0, -3.0, 1.0]));
+        let cancelled = E::sub(E::mul(x(), x()), E::pow(x(), 2));
+        assert_eq!(cancelled.poly_coeffs(Var::X), Some(vec![0.0]));
+    }
+
+    #[test]
+    fn test_from_coeffs() {
+        let p = E::from_coeffs(&[1, 2, 1], Var::X);
+        assert_eq!(p.to_string(), "((1 + (2 * X)) + (X^2))");
+        assert_eq!(p.poly_coeffs(Var::X), Some(vec![1.0, 2.0, 1.0]));
+        let q = E::from_coeffs(&[0, -1, 0, 4], Var::Y);
+        assert_eq!(q.to_string(), "((-1 * Y) + (4 * (Y^3)))");
+        assert_eq!(q.poly_coeffs(Var::Y), Some(vec![0.0, -1.0, 0.0, 4.0]));
+        assert_eq!(*E::from_coeffs(&[], Var::X), E::Const(Const::Numeric(0)));
+        assert_eq!(*E::from_coeffs(&[0, 0], Var::X), E::Const(Const::Numeric(0)));
+    }
+
+    #[test]
+    fn test_poly_coeffs_constant() {
+        let c = E::mul(E::constant(Const::Float(1.5)), E::constant(Const::Numeric(2)));
+        assert_eq!(c.poly_coeffs(Var::X), Some(vec![3.0]));
+        assert_eq!(E::inv(E::constant(Const::Numeric(4))).poly_coeffs(Var::Y), Some(vec![0.25]));
+    }
+
+    #[test]
+    fn test_poly_coeffs_not_polynomial() {
+        let x = || E::var(Var::X);
+        assert_eq!(E::add(x(), E::inv(x())).poly_coeffs(Var::X), None);
+        assert_eq!(E::func(String::from("sin"), x()).poly_coeffs(Var::X), None);
+        assert_eq!(E::mul(x(), E::var(Var::Y)).poly_coeffs(Var::X), None);
+        let a = E::constant(Const::Named(String::from("a")));
+        assert_eq!(E::mul(a, x()).poly_coeffs(Var::X), None);
+    }
+
+    #[test]
+    fn test_subexprs_pre_order() {
+        let xy = E::mul(E::var(Var::X), E::var(Var::Y));
+        assert_eq!(xy.subexprs(), [xy.as_ref(), &E::Var(Var::X), &E::Var(Var::Y)]);
+
+        let e = E::add(E::neg(E::var(Var::X)), E::func(String::from("f"), E::var(Var::Z)));
+        let shown: Vec<String> = e.subexprs().iter().map(|s| s.to_string()).collect();
+        assert_eq!(shown, ["(-(X) + f(Z))", "-(X)", "X", "f(Z)", "Z"]);
+        assert_eq!(e.subexprs().len(), e.op_histogram().values().sum::<usize>());
+    }
+
+    #[test]
+    fn test_with_max_depth_accepts_shallow() {
+        let e = *E::add(E::var(Var::X), E::neg(E::var(Var::Y)));
+        assert_eq!(e.depth(), 3);
+        assert_eq!(e.clone().with_max_depth(3), Ok(Box::new(e.clone())));
+        assert_eq!(e.with_max_depth(2), Err(TooDeep {depth: 3, max: 2}));
+        assert_eq!(E::var(Var::X).with_max_depth(1).map(|e| e.depth()), Ok(1));
+    }
+
+    #[test]
+    fn test_with_max_depth_rejects_deep() {
+        let mut e = E::var(Var::X);
+        for _ in 0..10_000 {
+            e = E::neg(e);
+        }
+        assert_eq!(e.depth(), 10_001);
+        assert_eq!(e.with_max_depth(100), Err(TooDeep {depth: 10_001, max: 100}));
+    }
+
+    #[test]
+    fn test_mul_depth() {
+        let x = || E::var(Var::X);
+        let y = || E::var(Var::Y);
+        assert_eq!(E::mul(x(), E::mul(y(), E::var(Var::Z))).mul_depth(), 2);
+        assert_eq!(E::add(x(), y()).mul_depth(), 0);
+        assert_eq!(E::inv(E::mul(x(), y())).mul_depth(), 2);
+        assert_eq!(E::neg(E::add(E::mul(x(), y()), x())).mul_depth(), 1);
+        assert_eq!(E::pow(x(), 2).mul_depth(), 0);
+        assert_eq!(E::pow(E::mul(x(), y()), 3).mul_depth(), 1);
+    }
+
+    #[test]
+    fn test_as_fraction_quotient() {
+        let x_over_y = E::mul(E::var(Var::X), E::inv(E::var(Var::Y)));
+        assert_eq!(x_over_y.as_fraction(), (E::var(Var::X), E::var(Var::Y)));
+        // -(X / (Y * 1/Z)) = -(X * Z) / Y
+        let nested = E::neg(E::mul(E::var(Var::X),
+                                   E::inv(E::mul(E::var(Var::Y), E::inv(E::var(Var::Z))))));
+        let (num, den) = nested.as_fraction();
+        assert_eq!(num, E::neg(E::mul(E::var(Var::X), E::var(Var::Z))));
+        assert_eq!(den, E::var(Var::Y));
+    }
+
+    #[test]
+    fn test_as_fraction_polynomial() {
+        let p = E::add(E::pow(E::var(Var::X), 2), E::mul(E::var(Var::X), E::var(Var::Y)));
+        assert_eq!(p.as_fraction(), (p.clone(), E::constant(Const::Numeric(1))));
+    }
+
+    #[test]
+    fn test_linearize_square() {
+        let square = E::mul(E::var(Var::X), E::var(Var::X));
+        let linear = square.clone().linearize(Var::X, 1.0).unwrap();
+        assert_eq!(linear.to_string(), "(1 + (2 * (X - 1)))");
+
+        let consts = HashMap::new();
+        for x in [0.9, 1.0, 1.05, 1.1] {
+            let vars = HashMap::from([(Var::X, x)]);
+            let exact = square.eval(&vars, &consts).unwrap();
+            let approx = linear.eval(&vars, &consts).unwrap();
+            assert!((exact - approx).abs() <= (x - 1.0) * (x - 1.0) + 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_linearize_fractional_point() {
+        let square = E::mul(E::var(Var::X), E::var(Var::X));
+        let line = square.linearize(Var::X, 0.5).unwrap();
+        assert_eq!(line.to_string(), "(0.25 + (1 * (X - 0.5)))");
+    }
+
+    #[test]
+    fn test_linearize_unevaluable() {
+        let expr = E::mul(E::var(Var::X), E::var(Var::Y));
+        assert!(expr.linearize(Var::X, 1.0).is_none());
+        let sqrt = E::func("sqrt".into(), E::var(Var::X));
+        assert!(sqrt.linearize(Var::X, 4.0).is_none());
+    }
+
+    #[test]
+    fn test_eval_derivative_matches_diff() {
+        let xy = E::mul(E::var(Var::X), E::var(Var::Y));
+        let vars = HashMap::from([(Var::X, 1.5), (Var::Y, -4.0)]);
+        let consts = HashMap::new();
+        for by in [Var::X, Var::Y, Var::Z] {
+            assert_eq!(xy.eval_derivative(by, &vars, &consts),
+                       xy.clone().diff(by).eval(&vars, &consts));
+        }
+    }
+
+    #[test]
+    fn test_eval_derivative_pow_inv_and_errors() {
+        // (X^3 - 1/X)' = 3X^2 + 1/X^2
+        let e = E::sub(E::pow(E::var(Var::X), 3), E::inv(E::var(Var::X)));
+        let vars = HashMap::from([(Var::X, 2.0)]);
+        let consts = HashMap::new();
+        assert_eq!(e.eval_derivative(Var::X, &vars, &consts), Ok(12.25));
+        assert_eq!(e.eval_derivative(Var::Y, &HashMap::new(), &consts),
+                   Err(EvalError::UnboundVar(Var::X)));
+        let a = E::constant(Const::Named(String::from("a")));
+        assert_eq!(a.eval_derivative(Var::X, &vars, &consts),
+                   Err(EvalError::UnboundConst(String::from("a"))));
+    }
+
     #[test]
     fn test_diff_neg() {
         let expr = E::neg(E::var(Var::X));
@@ -337,6 +1826,18 @@ mod tests {
         assert_eq!(d.to_string(), "-(1)");
     }
 
+    #[test]
+    fn test_diff_n_of_cube() {
+        let x = || E::var(Var::X);
+        let cube = E::mul(E::mul(x(), x()), x());
+        let second = cube.clone().diff_n(Var::X, 2);
+        assert_eq!(second.poly_coeffs(Var::X), Some(vec![0.0, 6.0]));
+        assert_eq!(second.eval(&HashMap::from([(Var::X, 2.0)]), &HashMap::new()), Ok(12.0));
+        assert_eq!(cube.clone().diff_n(Var::X, 3).to_string(), "6");
+        assert_eq!(cube.clone().diff_n(Var::X, 4).to_string(), "0");
+        assert_eq!(cube.clone().diff_n(Var::X, 0), cube);
+    }
+
     #[test]
     fn test_diff_mul() {
         let expr = E::mul(E::var(Var::X), E::var(Var::Y));
@@ -408,6 +1909,92 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_op_histogram() {
+        let big = E::add(
+            E::mul(E::add(E::var(Var::X), E::neg(E::var(Var::Y))), E::inv(E::var(Var::Z))),
+            E::add(E::func("f".into(), E::mul(E::var(Var::X), E::var(Var::Y))),
+                   E::func("g".into(), E::inv(E::var(Var::X)))),
+        );
+        let counts = big.op_histogram();
+        assert_eq!(counts, BTreeMap::from([("Add", 3), ("Func", 2), ("Inv", 2), ("Mul", 2),
+                                           ("Neg", 1), ("Var", 6)]));
+
+        let d = big.diff(Var::X).op_histogram();
+        assert_eq!(d["Func"], 2);
+        assert_eq!(d["Inv"], 4);
+        assert_eq!(d["Neg"], 4);
+        assert_eq!(d["Mul"], 11);
+        assert_eq!(d["Const"], 6);
+        assert_eq!(d.get("Pow"), None);
+    }
+
+    #[test]
+    fn test_erc_diff_matches_box_diff() {
+        // f(X * (Y + 1/(X * Z))) * -(X^3) nested a few times
+        let mut expr = E::var(Var::X);
+        for _ in 0..4 {
+            let inner = E::add(E::var(Var::Y), E::inv(E::mul(E::var(Var::X), E::var(Var::Z))));
+            let f = E::func("f".into(), E::mul(expr.clone(), inner));
+            expr = E::mul(f, E::neg(E::pow(expr, 3)));
+        }
+
+        let box_dd = expr.clone().diff(Var::X).diff(Var::X);
+        let rc_dd = ERc::from(expr.as_ref()).diff(Var::X).diff(Var::X);
+        assert_eq!(rc_dd.to_string(), box_dd.to_string());
+    }
+
+    #[test]
+    fn test_erc_diff_shares_subtrees() {
+        let f = E::mul(E::func("sin".into(), E::var(Var::X)), E::var(Var::Y));
+        let f_rc = ERc::from(f.as_ref());
+        let d = f_rc.diff(Var::X);
+        let (ERc::Mul(sin, y), ERc::Add(left, right)) = (&f_rc, d.as_ref()) else {
+            panic!("unexpected shape");
+        };
+        let (ERc::Mul(_, g), ERc::Mul(f_shared, _)) = (left.as_ref(), right.as_ref()) else {
+            panic!("unexpected shape");
+        };
+        assert!(Rc::ptr_eq(g, y));
+        assert!(Rc::ptr_eq(f_shared, sin));
+    }
+
+    #[test]
+    fn test_pool_shares_identical_subtrees() {
+        let mut pool = ExprPool::new();
+        let x = pool.build_var(Var::X);
+        let two = pool.build_constant(Const::Numeric(2));
+        let first = pool.build_mul(Rc::clone(&two), Rc::clone(&x));
+        let second = pool.build_mul(two, x);
+        assert!(Rc::ptr_eq(&first, &second));
+        let nodes = pool.len();
+        let sum = pool.build_add(Rc::clone(&first), second);
+        let ERc::Add(left, right) = sum.as_ref() else {
+            panic!("expected Add, got {:?}", sum);
+        };
+        assert!(Rc::ptr_eq(left, right));
+        assert_eq!(pool.len(), nodes + 1);
+        let x = pool.build_var(Var::X);
+        let two = pool.build_constant(Const::Numeric(2));
+        assert!(!Rc::ptr_eq(&first, &pool.build_mul(x, two)));
+    }
+
+    #[test]
+    fn test_pool_from_expr_to_string() {
+        let sin_x = E::func(String::from("sin"), E::var(Var::X));
+        let expr = E::sub(
+            E::mul(E::pow(sin_x.clone(), 2), E::inv(sin_x.clone())),
+            E::neg(E::add(sin_x, E::constant(Const::Named(String::from("a"))))),
+        );
+        let mut pool = ExprPool::new();
+        let pooled = pool.intern_expr(&expr);
+        assert_eq!(pooled.to_string(), expr.to_string());
+        // X, sin(X), sin(X)^2, 1/sin(X), product, a, sum, negation, difference
+        assert_eq!(pool.len(), 9);
+        assert!(Rc::ptr_eq(&pooled, &pool.intern_expr(&expr)));
+        assert_eq!(pool.len(), 9);
+    }
+
     #[test]
     fn test_arg_count_zeroary() {
         assert_eq!(E::constant(Const::Numeric(1)).arg_count(), 0);
@@ -421,11 +2008,170 @@ mod tests {
         assert_eq!(E::func("f".into(), E::var(Var::X)).arg_count(), 1);
     }
 
+    #[test]
+    fn test_eval_arithmetic() {
+        // (X + -(a)) * 1/(Y)
+        let expr = E::mul(
+            E::add(E::var(Var::X), E::neg(E::constant(Const::Named("a".into())))),
+            E::inv(E::var(Var::Y)),
+        );
+        let vars = HashMap::from([(Var::X, 7.0), (Var::Y, 2.0)]);
+        let consts = HashMap::from([("a".to_string(), 1.0)]);
+        assert_eq!(expr.eval(&vars, &consts), Ok(3.0));
+    }
+
+    #[test]
+    fn test_eval_unbound() {
+        let consts = HashMap::new();
+        assert_eq!(E::var(Var::Z).eval(&HashMap::new(), &consts),
+                   Err(EvalError::UnboundVar(Var::Z)));
+        assert_eq!(E::constant(Const::Named("b".into())).eval(&HashMap::new(), &consts),
+                   Err(EvalError::UnboundConst("b".into())));
+    }
+
+    #[test]
+    fn test_eval_with_fns_sin() {
+        let expr = E::func("sin".into(), E::var(Var::X));
+        let vars = HashMap::from([(Var::X, 0.0)]);
+        let fns: HashMap<String, fn(f64) -> f64> = HashMap::from([
+            ("sin".to_string(), f64::sin as fn(f64) -> f64),
+        ]);
+        assert_eq!(expr.eval_with_fns(&vars, &HashMap::new(), &fns), Ok(0.0));
+    }
+
+    #[test]
+    fn test_eval_unknown_func() {
+        let expr = E::func("cos".into(), E::var(Var::X));
+        let vars = HashMap::from([(Var::X, 0.0)]);
+        assert_eq!(expr.eval(&vars, &HashMap::new()),
+                   Err(EvalError::UnknownFunc("cos".into())));
+    }
+
+    #[test]
+    fn test_builder_pow() {
+        let expr = E::pow(E::var(Var::X), 3);
+        assert_eq!(expr.to_string(), "(X^3)");
+        assert_eq!(expr.arg_count(), 1);
+    }
+
+    #[test]
+    fn test_diff_pow() {
+        let d = E::pow(E::var(Var::X), 3).diff(Var::X);
+        assert_eq!(d.to_string(), "((3 * (X^2)) * 1)");
+        let d0 = E::pow(E::var(Var::X), 0).diff(Var::X);
+        assert_eq!(d0.to_string(), "0");
+    }
+
+    #[test]
+    fn test_eval_pow() {
+        let expr = E::pow(E::add(E::var(Var::X), E::constant(Const::Numeric(1))), 2);
+        let vars = HashMap::from([(Var::X, 2.0)]);
+        assert_eq!(expr.eval(&vars, &HashMap::new()), Ok(9.0));
+    }
+
+    #[test]
+    fn test_degree_in() {
+        let x_times_x = E::mul(E::var(Var::X), E::var(Var::X));
+        assert_eq!(x_times_x.degree_in(Var::X), 2);
+        assert_eq!(x_times_x.degree_in(Var::Y), 0);
+        let x_plus_y = E::add(E::var(Var::X), E::var(Var::Y));
+        assert_eq!(x_plus_y.degree_in(Var::X), 1);
+        assert_eq!(E::constant(Const::Numeric(7)).degree_in(Var::X), 0);
+        let cubed = E::pow(E::add(x_times_x, E::var(Var::X)), 3);
+        assert_eq!(cubed.degree_in(Var::X), 6);
+        let huge = E::pow(E::pow(E::var(Var::X), 65536), 65536);
+        assert_eq!(huge.degree_in(Var::X), u32::MAX);
+        assert_eq!(E::mul(huge.clone(), huge).degree_in(Var::X), u32::MAX);
+    }
+
     #[test]
     fn test_arg_count_binary() {
         assert_eq!(E::add(E::var(Var::X), E::var(Var::Y)).arg_count(), 2);
         assert_eq!(E::mul(E::var(Var::X), E::var(Var::Z)).arg_count(), 2);
     }
+
+    #[test]
+    fn test_try_eval_const() {
+        let n = |v| E::constant(Const::Numeric(v));
+        assert_eq!(E::add(n(2), E::mul(n(3), n(4))).try_eval_const(), Some(14));
+        assert_eq!(E::neg(E::sub(n(2), E::pow(n(3), 2))).try_eval_const(), Some(7));
+        assert_eq!(E::add(n(2), E::mul(n(3), E::var(Var::X))).try_eval_const(), None);
+        assert_eq!(E::add(n(1), E::constant(Const::Named("a".into()))).try_eval_const(), None);
+        assert_eq!(E::inv(n(2)).try_eval_const(), None);
+        assert_eq!(E::func("sin".into(), n(0)).try_eval_const(), None);
+        assert_eq!(E::mul(n(i64::MAX), n(2)).try_eval_const(), None);
+    }
+
+    #[test]
+    fn test_check_div_by_zero() {
+        let n = |v| E::constant(Const::Numeric(v));
+        let x_minus_x = E::inv(E::sub(E::var(Var::X), E::var(Var::X)));
+        assert_eq!(E::add(n(1), x_minus_x).check_div_by_zero(),
+                   Err(String::from("Division by zero in 1/((X - X))")));
+        assert_eq!(E::mul(E::var(Var::Y), E::inv(n(0))).check_div_by_zero(),
+                   Err(String::from("Division by zero in 1/(0)")));
+        let two_minus_two = E::func("sin".into(), E::inv(E::add(n(2), E::neg(n(2)))));
+        assert!(two_minus_two.check_div_by_zero().is_err());
+
+        let safe = E::inv(E::sub(E::var(Var::X), E::var(Var::Y)));
+        assert_eq!(safe.check_div_by_zero(), Ok(()));
+        assert_eq!(E::inv(E::add(n(2), n(3))).check_div_by_zero(), Ok(()));
+    }
+
+    #[test]
+    fn test_to_string_with_default_matches_display() {
+        let x = || E::var(Var::X);
+        let minus_two = E::constant(Const::Numeric(-2));
+        let e = E::sub(E::neg(E::mul(x(), E::inv(E::add(x(), E::var(Var::Y))))),
+                       E::func("sin".into(), E::pow(E::add(x(), minus_two), 3)));
+        assert_eq!(e.to_string_with(&DisplayOptions::default()), e.to_string());
+    }
+
+    #[test]
+    fn test_to_string_with_mul_symbol() {
+        let xy = E::mul(E::var(Var::X), E::var(Var::Y));
+        let dot = DisplayOptions {mul_symbol: "·", ..DisplayOptions::default()};
+        assert_eq!(xy.to_string_with(&dot), "(X·Y)");
+        let implicit = DisplayOptions {mul_symbol: "", redundant_parens: false, ..dot};
+        assert_eq!(xy.to_string_with(&implicit), "XY");
+        let e = E::mul(E::add(E::var(Var::X), E::constant(Const::Numeric(1))), xy);
+        assert_eq!(e.to_string_with(&implicit), "(X + 1)XY");
+    }
+
+    #[test]
+    fn test_to_string_with_minimal_parens() {
+        let opts = DisplayOptions {redundant_parens: false, ..DisplayOptions::default()};
+        let (x, y) = (|| E::var(Var::X), || E::var(Var::Y));
+        assert_eq!(E::sub(x(), E::add(y(), x())).to_string_with(&opts), "X - (Y + X)");
+        assert_eq!(E::add(E::sub(x(), y()), x()).to_string_with(&opts), "X - Y + X");
+        assert_eq!(E::neg(E::mul(x(), y())).to_string_with(&opts), "-(X * Y)");
+        assert_eq!(E::pow(E::neg(x()), 2).to_string_with(&opts), "(-X)^2");
+        assert_eq!(E::mul(x(), E::inv(y())).to_string_with(&opts), "X * 1/Y");
+        assert_eq!(E::inv(E::mul(x(), y())).to_string_with(&opts), "1/(X * Y)");
+        let power = DisplayOptions {inverse_as_power: true, ..opts};
+        assert_eq!(E::inv(E::mul(x(), y())).to_string_with(&power), "(X * Y)^-1");
+        assert_eq!(E::mul(x(), E::inv(y())).to_string_with(&power), "X * Y^-1");
+    }
+
+    #[test]
+    fn test_tokens() {
+        use Token::*;
+        let e = E::add(E::constant(Const::Numeric(2)), E::var(Var::X));
+        assert_eq!(e.tokens(), vec![LParen, Num(2), Op("+"), Ident("X".into()), RParen]);
+
+        let render = |tokens: Vec<Token>| tokens.iter().map(|t| match t {
+            LParen => "(".to_string(),
+            RParen => ")".to_string(),
+            Op(op @ ("+" | "*")) => format!(" {} ", op),
+            Op(op) => op.to_string(),
+            Ident(s) => s.clone(),
+            Num(n) => n.to_string(),
+            Float(x) => x.to_string(),
+        }).collect::<String>();
+        let y_sq = E::pow(E::var(Var::Y), 2);
+        let e = E::neg(E::inv(E::func("sin".into(), E::mul(y_sq, E::constant(Const::Float(0.5))))));
+        assert_eq!(render(e.tokens()), e.to_string());
+    }
 }
 
 
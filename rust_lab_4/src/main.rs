@@ -1,9 +1,10 @@
-use std::{collections::BTreeSet, time, hint::black_box, io::{self, Read, Write}};
+use std::{collections::{BTreeSet, HashMap}, time, hint::black_box, io::{self, Read, Write}};
 use core::{num::NonZero};
 use std::net::{TcpListener, TcpStream};
 use std::fs;
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::thread;
 
 fn main() {
@@ -17,6 +18,9 @@ fn main() {
     };
     let set = divisors(n);
     println!("divisors: {:?}", set);
+    println!("proper divisors: {:?}, abundant: {}, deficient: {}",
+             proper_divisors(n), is_abundant(n), is_deficient(n));
+    println!("aliquot sequence: {:?}", aliquot_sequence(n, 10));
 
     // Ex. 2
     //let v = vec![1,2,3,4,6,5,7,8,9,10]; // Uncomment to check panic
@@ -41,23 +45,71 @@ fn main() {
             return;
         }
     };
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(stream) {
-                        eprintln!("Error in client handling: {}", e);
+    let mut logger = SharedLogger::new(StdLogger);
+    let state = ServerState::new(ListingCache::new(time::Duration::from_secs(5)));
+    if let Err(e) = run_server(listener, state, logger.clone()) {
+        logger.error(&format!("Server error: {}", e));
+    }
+}
+
+// What every handler thread shares.
+#[derive(Clone)]
+struct ServerState {
+    cache: ListingCache,
+    shutdown: Arc<AtomicBool>,
+    // Request lines received over all connections, STATUS included.
+    requests: Arc<AtomicU64>,
+}
+
+impl ServerState {
+    fn new(cache: ListingCache) -> Self {
+        Self {cache, shutdown: Arc::default(), requests: Arc::default()}
+    }
+}
+
+// How long the accept loop sleeps when nobody is connecting before checking for shutdown.
+const ACCEPT_POLL: time::Duration = time::Duration::from_millis(50);
+
+// Accepts clients until one of them sends SHUTDOWN, then waits for the handlers still running.
+// Every handler logs to a clone of `logger`.
+fn run_server(listener: TcpListener, state: ServerState, mut logger: SharedLogger)
+    -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut handlers = Vec::new();
+    while !state.shutdown.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = stream.set_nonblocking(false) {
+                    logger.error(&format!("Connection error: {}", e));
+                    continue;
+                }
+                let state = state.clone();
+                let mut logger = logger.clone();
+                handlers.push(thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, &mut logger, &state) {
+                        logger.error(&format!("Error in client handling: {}", e));
                     }
-                });
+                    logger.info(&format!("Directory reads so far: {}", state.cache.read_count()));
+                }));
             }
-            Err(e) => eprintln!("Connection error: {}", e),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+            Err(e) => logger.error(&format!("Connection error: {}", e)),
         }
+        handlers.retain(|h| !h.is_finished());
     }
+
+    logger.info(&format!("Shutting down, waiting for {} clients", handlers.len()));
+    for handler in handlers {
+        if handler.join().is_err() {
+            logger.error("Client handler panicked");
+        }
+    }
+    Ok(())
 }
 
 fn divisors(n: NonZero<u32>) -> BTreeSet<NonZero<u32>> {
     let mut tree = BTreeSet::<NonZero<u32>>::new();
-    for i in 1..n.isqrt().get() {
+    for i in 1..=n.isqrt().get() {
         if n.get().is_multiple_of(i) {
             if let Some(v) = NonZero::new(i) {
                 tree.insert(v);
@@ -70,6 +122,47 @@ fn divisors(n: NonZero<u32>) -> BTreeSet<NonZero<u32>> {
     tree
 }
 
+fn proper_divisors(n: NonZero<u32>) -> BTreeSet<NonZero<u32>> {
+    let mut tree = divisors(n);
+    tree.remove(&n);
+    tree
+}
+
+// u64, since the sum of proper divisors of a large u32 can overflow it.
+fn proper_divisor_sum(n: NonZero<u32>) -> u64 {
+    proper_divisors(n).iter().map(|d| d.get() as u64).sum()
+}
+
+fn is_abundant(n: NonZero<u32>) -> bool {
+    proper_divisor_sum(n) > n.get() as u64
+}
+
+fn is_deficient(n: NonZero<u32>) -> bool {
+    proper_divisor_sum(n) < n.get() as u64
+}
+
+// Starts with `start` and ends at 0, before the first repeated term, at `max_len` terms,
+// or when the next term no longer fits in u32.
+fn aliquot_sequence(start: NonZero<u32>, max_len: usize) -> Vec<u32> {
+    let mut sequence = Vec::new();
+    let mut term = Some(start);
+    while sequence.len() < max_len {
+        let Some(n) = term else {
+            sequence.push(0);
+            break;
+        };
+        if sequence.contains(&n.get()) {
+            break;
+        }
+        sequence.push(n.get());
+        match u32::try_from(proper_divisor_sum(n)) {
+            Ok(next) => term = NonZero::new(next),
+            Err(_) => break,
+        }
+    }
+    sequence
+}
+
 fn assert_sorted(buf: &[i32]) {
     buf.windows(2).for_each(|p| {
         if p[0] > p[1] {
@@ -78,6 +171,18 @@ fn assert_sorted(buf: &[i32]) {
     })
 }
 
+// Every reply starts with a status line, `OK <n>` followed by the body or `ERR <reason>`.
+// For GET n is the number of bytes of the file, for every other command the number of lines of
+// the body: the entries of a listing, one for the count of STATUS and the `BYE` of QUIT/SHUTDOWN.
+fn send_ok(stream: &mut TcpStream, n: usize, body: &[u8]) -> io::Result<()> {
+    bulk_write(stream, format!("OK {}\n", n).as_bytes())?;
+    bulk_write(stream, body)
+}
+
+fn send_err(stream: &mut TcpStream, reason: &str) -> io::Result<()> {
+    bulk_write(stream, format!("ERR {}\n", reason).as_bytes())
+}
+
 // Ex. 4
 fn bulk_write(stream: &mut TcpStream, buf: &[u8]) -> io::Result<()> {
     let mut written = 0;
@@ -111,56 +216,753 @@ fn bulk_read(stream: &mut TcpStream, size: usize) -> io::Result<Vec<u8>> {
     Ok(buf)
 }
 
+// Sink for server activity, so it can be redirected or captured.
+trait Logger {
+    fn info(&mut self, line: &str);
+    fn error(&mut self, line: &str);
+}
+
+#[derive(Clone, Copy)]
+struct StdLogger;
+
+// One sink for all handler threads, locked for each line.
+#[derive(Clone)]
+struct SharedLogger(Arc<Mutex<dyn Logger + Send>>);
+
+impl SharedLogger {
+    fn new(logger: impl Logger + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(logger)))
+    }
+}
+
+impl Logger for SharedLogger {
+    fn info(&mut self, line: &str) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).info(line);
+    }
+
+    fn error(&mut self, line: &str) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).error(line);
+    }
+}
+
+impl Logger for StdLogger {
+    fn info(&mut self, line: &str) {
+        println!("{}", line);
+    }
+
+    fn error(&mut self, line: &str) {
+        eprintln!("{}", line);
+    }
+}
+
+// Longest request line accepted, the newline included.
+const MAX_LINE: usize = 100;
+
+// A request line without its newline, or a line longer than MAX_LINE.
+enum Request {
+    Line(Vec<u8>),
+    TooLong,
+}
+
+// Splits what a client sends into lines, bytes after the last newline wait for the next read.
+#[derive(Default)]
+struct RequestReader {
+    pending: Vec<u8>,
+    // Set while skipping the rest of a line that didn't fit into MAX_LINE.
+    discarding: bool,
+}
+
+impl RequestReader {
+    fn next_request(&mut self, stream: &mut TcpStream) -> io::Result<Request> {
+        loop {
+            let newline = self.pending.iter().position(|&b| b == b'\n');
+            // Only the rest of the oversized line goes, what follows its newline is a new request.
+            if self.discarding {
+                match newline {
+                    Some(end) => {
+                        self.pending.drain(..=end);
+                        self.discarding = false;
+                        continue;
+                    }
+                    None => self.pending.clear(),
+                }
+            } else if let Some(end) = newline {
+                let mut line: Vec<u8> = self.pending.drain(..=end).collect();
+                line.pop();
+                return Ok(if end < MAX_LINE { Request::Line(line) } else { Request::TooLong });
+            } else if self.pending.len() >= MAX_LINE {
+                self.pending.clear();
+                self.discarding = true;
+                return Ok(Request::TooLong);
+            }
+            self.pending.extend(bulk_read(stream, MAX_LINE)?);
+        }
+    }
+}
+
 // Ex. 7
-fn handle_client (mut stream: TcpStream) -> io::Result<()> {
-    println!("New connection {:?}", stream.peer_addr()?);
+fn handle_client (mut stream: TcpStream, logger: &mut dyn Logger, state: &ServerState)
+    -> io::Result<()> {
+    logger.info(&format!("New connection {:?}", stream.peer_addr()?));
 
-    let data = bulk_read(&mut stream, 100)?;
-    if data.is_empty() {
-        println!("No data");
-        return Ok(());
+    let mut reader = RequestReader::default();
+    loop {
+        let data = match reader.next_request(&mut stream) {
+            Ok(Request::Line(d)) => d,
+            Ok(Request::TooLong) => {
+                logger.error("Request line too long");
+                send_err(&mut stream, "Path too long")?;
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                logger.info("Client disconnected");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let line = match String::from_utf8(data) {
+            Ok(s) => s.trim().to_string(),
+            Err(_) => {
+                logger.error("Request is not valid UTF-8");
+                send_err(&mut stream, "Bad path")?;
+                continue;
+            }
+        };
+        // Blank lines get a usage hint and aren't counted as requests.
+        if line.is_empty() {
+            logger.info("Empty request");
+            send_err(&mut stream, "Send a command, e.g. LIST <dir>")?;
+            continue;
+        }
+        logger.info(&format!("Request {:?}", line));
+        let served = state.requests.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let (command, argument) = line.split_once(' ').unwrap_or((&line, ""));
+        let path = Path::new(argument.trim());
+        match command {
+            "LIST" | "GET" if path.as_os_str().is_empty() => send_err(&mut stream, "Send a path")?,
+            "LIST" => send_listing(&mut stream, path, logger, &state.cache)?,
+            "GET" => send_file(&mut stream, path, logger)?,
+            "QUIT" => {
+                send_ok(&mut stream, 1, b"BYE\n")?;
+                return Ok(());
+            }
+            "STATUS" => {
+                send_ok(&mut stream, 1, format!("{}\n", served).as_bytes())?;
+            }
+            "SHUTDOWN" => {
+                state.shutdown.store(true, Ordering::SeqCst);
+                send_ok(&mut stream, 1, b"BYE\n")?;
+                return Ok(());
+            }
+            _ => send_err(&mut stream, "unknown")?,
+        }
     }
+}
 
-    let path_str = match String::from_utf8(data) {
-        Ok(s) => s.trim().to_string(),
-        Err(_) => {
-            bulk_write(&mut stream,b"Bad path\n")?;
+fn send_file(stream: &mut TcpStream, path: &Path, logger: &mut dyn Logger) -> io::Result<()> {
+    let contents = match fs::read(path) {
+        Ok(c) => c,
+        Err(e) => {
+            logger.error(&format!("Error while reading file {:?}: {}", path, e));
+            send_err(stream, "Bad file")?;
             return Ok(());
         }
     };
 
-    let path = match PathBuf::from_str(&path_str) {
-        Ok(p) => p,
-        Err(_) => {
-            bulk_write(&mut stream,b"Bad path\n")?;
+    send_ok(stream, contents.len(), &contents)?;
+    logger.info(&format!("Contents of the file sent {:?}", path));
+
+    Ok(())
+}
+
+fn send_listing(stream: &mut TcpStream, path: &Path, logger: &mut dyn Logger,
+                cache: &ListingCache) -> io::Result<()> {
+    let listing = match cache.listing(path, logger) {
+        Ok(Some(l)) => l,
+        Ok(None) => return stream_listing(stream, path, logger),
+        Err(e) => {
+            logger.error(&format!("Error while opening directory {:?}: {}", path, e));
+            send_err(stream, "Bad dir")?;
             return Ok(());
         }
     };
 
-    let entries = match fs::read_dir(&path) {
-        Ok(e) => e,
+    bulk_write(stream, format!("OK {}\n", listing.lines().count()).as_bytes())?;
+    write_listing(stream, listing.lines())?;
+    logger.info(&format!("Contents of the directory sent {:?}", path));
+
+    Ok(())
+}
+
+// For directories too large to cache: one pass counts the entries for the status line, a second
+// one sends the names as they are read, so the listing is never held in memory as a whole.
+// If the directory shrinks in between the reply can't be completed and the connection is dropped.
+fn stream_listing(stream: &mut TcpStream, path: &Path, logger: &mut dyn Logger)
+    -> io::Result<()> {
+    let names = |path| -> io::Result<_> {
+        Ok(fs::read_dir(path)?.filter_map(Result::ok)
+            .map(|e| e.file_name().to_string_lossy().into_owned()))
+    };
+    let (count, entries) = match names(path).and_then(|n| Ok((n.count(), names(path)?))) {
+        Ok(found) => found,
         Err(e) => {
-            eprintln!("Error while opening directory {:?}: {}", path, e);
-            bulk_write(&mut stream, b"Bad dir\n")?;
-            return Ok(());
+            logger.error(&format!("Error while opening directory {:?}: {}", path, e));
+            return send_err(stream, "Bad dir");
         }
     };
 
+    bulk_write(stream, format!("OK {}\n", count).as_bytes())?;
+    let mut sent = 0;
+    write_listing(stream, entries.take(count).inspect(|_| sent += 1))?;
+    if sent < count {
+        return Err(io::Error::other(format!("{:?} changed while being listed", path)));
+    }
+    logger.info(&format!("Contents of the directory streamed {:?}", path));
+    Ok(())
+}
+
+// Names written and flushed per chunk of a listing.
+const LISTING_CHUNK: usize = 64;
+
+// Sends the names a chunk at a time instead of in one write.
+fn write_listing<W: Write, S: AsRef<str>>(out: &mut W, names: impl IntoIterator<Item = S>)
+    -> io::Result<()> {
+    let mut chunk = String::new();
+    for (i, name) in names.into_iter().enumerate() {
+        chunk.push_str(name.as_ref());
+        chunk.push('\n');
+        if (i + 1) % LISTING_CHUNK == 0 {
+            out.write_all(chunk.as_bytes())?;
+            out.flush()?;
+            chunk.clear();
+        }
+    }
+    if !chunk.is_empty() {
+        out.write_all(chunk.as_bytes())?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+// Most directories ListingCache::new keeps at once.
+const MAX_CACHED_LISTINGS: usize = 256;
+
+// Directories with more entries aren't cached by ListingCache::new but streamed.
+const MAX_CACHED_ENTRIES: usize = 1024;
+
+// Directory listings shared by all handler threads, read again once older than `ttl`.
+// Expired listings are dropped on every insert, the oldest one too when `capacity` is reached.
+// Directories of more than `max_entries` entries aren't kept.
+#[derive(Clone)]
+struct ListingCache {
+    entries: Arc<Mutex<HashMap<PathBuf, (time::Instant, String)>>>,
+    ttl: time::Duration,
+    capacity: usize,
+    max_entries: usize,
+    reads: Arc<AtomicUsize>,
+}
+
+impl ListingCache {
+    fn new(ttl: time::Duration) -> Self {
+        Self::with_limits(ttl, MAX_CACHED_LISTINGS, MAX_CACHED_ENTRIES)
+    }
+
+    fn with_limits(ttl: time::Duration, capacity: usize, max_entries: usize) -> Self {
+        Self {entries: Arc::default(), ttl, capacity, max_entries, reads: Arc::default()}
+    }
+
+    // How many times a directory was actually read.
+    fn read_count(&self) -> usize {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, (time::Instant, String)>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // The lock isn't held while reading, so a slow directory doesn't hold up the others.
+    // None for a directory with more than `max_entries` entries.
+    fn listing(&self, path: &Path, logger: &mut dyn Logger) -> io::Result<Option<String>> {
+        if let Some((read_at, listing)) = self.lock().get(path)
+            && read_at.elapsed() < self.ttl {
+            return Ok(Some(listing.clone()));
+        }
+
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let Some(listing) = read_listing(path, logger, self.max_entries)? else {
+            return Ok(None);
+        };
+        let mut entries = self.lock();
+        entries.retain(|_, (read_at, _)| read_at.elapsed() < self.ttl);
+        if entries.len() >= self.capacity && !entries.contains_key(path) {
+            let oldest = entries.iter().min_by_key(|(_, (read_at, _))| *read_at)
+                .map(|(oldest, _)| oldest.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(path.to_path_buf(), (time::Instant::now(), listing.clone()));
+        Ok(Some(listing))
+    }
+}
+
+// Stops with None once there are more than `max_entries` names.
+fn read_listing(path: &Path, logger: &mut dyn Logger, max_entries: usize)
+    -> io::Result<Option<String>> {
+    let entries = fs::read_dir(path)?;
+
     let mut listing = String::new();
+    let mut count = 0;
     for entry in entries {
         match entry {
+            Ok(_) if count == max_entries => return Ok(None),
+            // Names that aren't UTF-8 are listed with replacement characters instead of skipped.
             Ok(e) => {
-                if let Some(name) = e.file_name().to_str() {
-                    listing.push_str(name);
-                    listing.push('\n');
-                }
+                count += 1;
+                listing.push_str(&e.file_name().to_string_lossy());
+                listing.push('\n');
             }
-            Err(err) => eprintln!("Error while iterating through the catalog: {}", err),
+            Err(err) =>
+                logger.error(&format!("Error while iterating through the catalog: {}", err)),
         }
     }
+    Ok(Some(listing))
+}
 
-    bulk_write(&mut stream, listing.as_bytes())?;
-    println!("Contents of the directory sent {:?}", path);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
 
-    Ok(())
-}
\ No newline at end of file
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_lab_4_{}_{}", std::process::id(), name))
+    }
+
+    fn nz(n: u32) -> NonZero<u32> {
+        NonZero::new(n).unwrap()
+    }
+
+    fn values(set: BTreeSet<NonZero<u32>>) -> Vec<u32> {
+        set.into_iter().map(NonZero::get).collect()
+    }
+
+    #[test]
+    fn divisors_include_square_root() {
+        assert_eq!(values(divisors(nz(1))), [1]);
+        assert_eq!(values(divisors(nz(6))), [1, 2, 3, 6]);
+        assert_eq!(values(divisors(nz(16))), [1, 2, 4, 8, 16]);
+    }
+
+    #[test]
+    fn proper_divisors_exclude_n() {
+        assert_eq!(values(proper_divisors(nz(6))), [1, 2, 3]);
+        assert_eq!(values(proper_divisors(nz(1))), Vec::<u32>::new());
+        assert_eq!(values(proper_divisors(nz(13))), [1]);
+    }
+
+    #[test]
+    fn abundant_and_deficient() {
+        assert!(is_abundant(nz(12)));
+        assert!(!is_deficient(nz(12)));
+        assert!(is_deficient(nz(8)));
+        assert!(!is_abundant(nz(8)));
+        // perfect numbers are neither
+        assert!(!is_abundant(nz(6)) && !is_deficient(nz(6)));
+        assert!(is_deficient(nz(1)));
+    }
+
+    #[test]
+    fn aliquot_sequence_of_perfect_number_is_constant() {
+        assert_eq!(aliquot_sequence(nz(6), 10), [6]);
+        assert_eq!(aliquot_sequence(nz(28), 10), [28]);
+        // amicable pair
+        assert_eq!(aliquot_sequence(nz(220), 10), [220, 284]);
+    }
+
+    #[test]
+    fn aliquot_sequence_terminates_at_zero() {
+        assert_eq!(aliquot_sequence(nz(10), 10), [10, 8, 7, 1, 0]);
+        assert_eq!(aliquot_sequence(nz(1), 10), [1, 0]);
+        assert_eq!(aliquot_sequence(nz(10), 3), [10, 8, 7]);
+        assert_eq!(aliquot_sequence(nz(10), 0), Vec::<u32>::new());
+    }
+
+    #[derive(Default)]
+    struct MemoryLogger {
+        lines: Vec<String>,
+    }
+
+    impl Logger for MemoryLogger {
+        fn info(&mut self, line: &str) {
+            self.lines.push(format!("info: {}", line));
+        }
+
+        fn error(&mut self, line: &str) {
+            self.lines.push(format!("error: {}", line));
+        }
+    }
+
+    fn connect() -> (TcpStream, thread::JoinHandle<MemoryLogger>) {
+        connect_with(ListingCache::new(time::Duration::from_secs(60)))
+    }
+
+    // The server thread hands back everything it logged.
+    fn connect_with(cache: ListingCache) -> (TcpStream, thread::JoinHandle<MemoryLogger>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut logger = MemoryLogger::default();
+            handle_client(stream, &mut logger, &ServerState::new(cache)).unwrap();
+            logger
+        });
+        (TcpStream::connect(addr).unwrap(), server)
+    }
+
+    fn send(client: &mut TcpStream, line: &str, response_len: usize) -> Vec<u8> {
+        client.write_all(line.as_bytes()).unwrap();
+        let mut response = vec![0u8; response_len];
+        client.read_exact(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn session_list_get_quit() {
+        let dir = temp_path("session");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "first line\nsecond line\n").unwrap();
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        let (mut client, server) = connect();
+
+        let listing = send(&mut client, &format!("LIST {}\n", dir.display()), 17);
+        let listing = String::from_utf8(listing).unwrap();
+        let mut lines = listing.lines();
+        assert_eq!(lines.next(), Some("OK 2"));
+        let mut names: Vec<&str> = lines.collect();
+        names.sort();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+
+        let contents = send(&mut client, &format!("GET {}\n", dir.join("a.txt").display()), 29);
+        assert_eq!(contents, b"OK 23\nfirst line\nsecond line\n");
+
+        assert_eq!(send(&mut client, "QUIT\n", 9), b"OK 1\nBYE\n");
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        server.join().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shutdown_waits_for_open_sessions() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = ServerState::new(ListingCache::new(time::Duration::from_secs(60)));
+        let logger = Arc::new(Mutex::new(MemoryLogger::default()));
+        let sink = SharedLogger(logger.clone());
+        let server = thread::spawn(move || run_server(listener, state, sink).unwrap());
+
+        let mut busy = TcpStream::connect(addr).unwrap();
+        assert_eq!(send(&mut busy, "PING\n", 12), b"ERR unknown\n");
+        let mut control = TcpStream::connect(addr).unwrap();
+        assert_eq!(send(&mut control, "SHUTDOWN\n", 9), b"OK 1\nBYE\n");
+
+        // the open session is still served until it quits
+        assert_eq!(send(&mut busy, "PING\n", 12), b"ERR unknown\n");
+        assert!(!server.is_finished());
+        assert_eq!(send(&mut busy, "QUIT\n", 9), b"OK 1\nBYE\n");
+        server.join().unwrap();
+        let lines = &logger.lock().unwrap().lines;
+        assert!(lines.iter().any(|l| l.starts_with("info: Shutting down")));
+    }
+
+    #[test]
+    fn handlers_log_to_the_server_logger() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = ServerState::new(ListingCache::new(time::Duration::from_secs(60)));
+        let logger = Arc::new(Mutex::new(MemoryLogger::default()));
+        let sink = SharedLogger(logger.clone());
+        let server = thread::spawn(move || run_server(listener, state, sink).unwrap());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(send(&mut client, "PING\n", 12), b"ERR unknown\n");
+        assert_eq!(send(&mut client, "SHUTDOWN\n", 9), b"OK 1\nBYE\n");
+        server.join().unwrap();
+        let lines = &logger.lock().unwrap().lines;
+        assert!(lines.contains(&"info: Request \"PING\"".to_string()));
+        assert!(lines.iter().any(|l| l.starts_with("info: Directory reads so far")));
+    }
+
+    #[test]
+    fn empty_request_gets_usage_hint() {
+        let (mut client, server) = connect();
+        let hint = b"ERR Send a command, e.g. LIST <dir>\n";
+        assert_eq!(send(&mut client, "\n", hint.len()), hint);
+        assert_eq!(send(&mut client, "   \n", hint.len()), hint);
+        assert_eq!(send(&mut client, "LIST \n", 16), b"ERR Send a path\n");
+        assert_eq!(send(&mut client, "GET\n", 16), b"ERR Send a path\n");
+        assert_eq!(send(&mut client, "STATUS\n", 7), b"OK 1\n3\n");
+        send(&mut client, "QUIT\n", 9);
+        let logger = server.join().unwrap();
+        assert_eq!(logger.lines.iter().filter(|l| *l == "info: Empty request").count(), 2);
+    }
+
+    #[test]
+    fn status_counts_requests_of_all_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = ServerState::new(ListingCache::new(time::Duration::from_secs(60)));
+        let server = thread::spawn(move || {
+            run_server(listener, state, SharedLogger::new(MemoryLogger::default())).unwrap();
+        });
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        assert_eq!(send(&mut first, "PING\n", 12), b"ERR unknown\n");
+        assert_eq!(send(&mut first, "STATUS\n", 7), b"OK 1\n2\n");
+        let mut second = TcpStream::connect(addr).unwrap();
+        assert_eq!(send(&mut second, "PING\n", 12), b"ERR unknown\n");
+        assert_eq!(send(&mut second, "STATUS\n", 7), b"OK 1\n4\n");
+
+        send(&mut first, "QUIT\n", 9);
+        send(&mut second, "SHUTDOWN\n", 9);
+        server.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn listing_keeps_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = temp_path("non_utf8");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(std::ffi::OsStr::from_bytes(b"bad\xffname")), "x").unwrap();
+
+        let listing = read_listing(&dir, &mut MemoryLogger::default(), 10).unwrap();
+        assert_eq!(listing.as_deref(), Some("bad\u{fffd}name\n"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn listing_cache_evicts_expired_and_oldest() {
+        let dirs: Vec<PathBuf> = (0..3).map(|i| temp_path(&format!("evict_{}", i))).collect();
+        for dir in &dirs {
+            fs::create_dir_all(dir).unwrap();
+        }
+        let mut logger = MemoryLogger::default();
+
+        let cache = ListingCache::with_limits(time::Duration::from_secs(60), 2, 10);
+        for dir in &dirs {
+            cache.listing(dir, &mut logger).unwrap();
+        }
+        assert_eq!(cache.lock().len(), 2);
+        assert!(!cache.lock().contains_key(&dirs[0]));
+        cache.listing(&dirs[2], &mut logger).unwrap();
+        assert_eq!(cache.read_count(), 3);
+
+        let expiring = ListingCache::new(time::Duration::ZERO);
+        for dir in &dirs {
+            expiring.listing(dir, &mut logger).unwrap();
+        }
+        assert_eq!(expiring.lock().len(), 1);
+        for dir in dirs {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn listing_is_cached_within_ttl() {
+        let dir = temp_path("cached");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        let cache = ListingCache::new(time::Duration::from_secs(60));
+        let (mut client, server) = connect_with(cache.clone());
+
+        let list = format!("LIST {}\n", dir.display());
+        assert_eq!(send(&mut client, &list, 11), b"OK 1\na.txt\n");
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        assert_eq!(send(&mut client, &list, 11), b"OK 1\na.txt\n");
+        assert_eq!(cache.read_count(), 1);
+        send(&mut client, "QUIT\n", 9);
+        server.join().unwrap();
+
+        // A second connection shares the same cache.
+        let (mut client, server) = connect_with(cache.clone());
+        assert_eq!(send(&mut client, &list, 11), b"OK 1\na.txt\n");
+        drop(client);
+        server.join().unwrap();
+        assert_eq!(cache.read_count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expired_listing_is_read_again() {
+        let dir = temp_path("expired");
+        fs::create_dir_all(&dir).unwrap();
+        let cache = ListingCache::new(time::Duration::ZERO);
+        let mut logger = MemoryLogger::default();
+        assert_eq!(cache.listing(&dir, &mut logger).unwrap().as_deref(), Some(""));
+        fs::write(dir.join("new.txt"), "").unwrap();
+        assert_eq!(cache.listing(&dir, &mut logger).unwrap().as_deref(), Some("new.txt\n"));
+        assert_eq!(cache.read_count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Remembers every flush, so tests can see how output was split.
+    #[derive(Default)]
+    struct ChunkWriter {
+        data: Vec<u8>,
+        flushed_at: Vec<usize>,
+    }
+
+    impl Write for ChunkWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed_at.push(self.data.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn listing_is_written_in_chunks() {
+        let listing: String = (0..150).map(|i| format!("file_{:03}\n", i)).collect();
+        let mut out = ChunkWriter::default();
+        write_listing(&mut out, listing.lines()).unwrap();
+        assert_eq!(out.data, listing.as_bytes());
+        // 64 + 64 + 22 names of 9 bytes each
+        assert_eq!(out.flushed_at, [576, 1152, 1350]);
+
+        let mut out = ChunkWriter::default();
+        write_listing(&mut out, "".lines()).unwrap();
+        assert!(out.data.is_empty() && out.flushed_at.is_empty());
+    }
+
+    #[test]
+    fn large_listing_arrives_complete() {
+        let dir = temp_path("large");
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..200 {
+            fs::write(dir.join(format!("entry_{:03}", i)), "").unwrap();
+        }
+        // too many entries to be cached, so the names are streamed
+        let cache = ListingCache::with_limits(time::Duration::from_secs(60), 10, 100);
+        let (mut client, server) = connect_with(cache.clone());
+
+        // status line and 200 names of 9 bytes plus a newline
+        let listing = send(&mut client, &format!("LIST {}\n", dir.display()), 7 + 200 * 10);
+        let listing = String::from_utf8(listing).unwrap();
+        let mut lines = listing.lines();
+        assert_eq!(lines.next(), Some("OK 200"));
+        let mut names: Vec<&str> = lines.collect();
+        names.sort();
+        let expected: Vec<String> = (0..200).map(|i| format!("entry_{:03}", i)).collect();
+        assert_eq!(names, expected);
+        assert!(cache.lock().is_empty());
+
+        drop(client);
+        server.join().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn status_line_counts_entries() {
+        let dir = temp_path("status");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["x", "y", "z"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let (mut client, server) = connect();
+
+        let listing = send(&mut client, &format!("LIST {}\n", dir.display()), 15);
+        let listing = String::from_utf8(listing).unwrap();
+        let (status, body) = listing.split_once('\n').unwrap();
+        assert_eq!(status, "OK 4");
+        assert_eq!(body.lines().count(), 4);
+        let empty = send(&mut client, &format!("GET {}\n", dir.join("x").display()), 5);
+        assert_eq!(empty, b"OK 0\n");
+
+        drop(client);
+        server.join().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn session_errors_keep_session_open() {
+        let (mut client, server) = connect();
+        assert_eq!(send(&mut client, "DELETE /\n", 12), b"ERR unknown\n");
+        let missing = temp_path("missing");
+        let list = format!("LIST {}\n", missing.display());
+        assert_eq!(send(&mut client, &list, 12), b"ERR Bad dir\n");
+        let get = format!("GET {}\n", missing.display());
+        assert_eq!(send(&mut client, &get, 13), b"ERR Bad file\n");
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_line_is_rejected() {
+        let (mut client, server) = connect();
+        let line = "A".repeat(200);
+        assert_eq!(send(&mut client, &line, 18), b"ERR Path too long\n");
+        drop(client);
+        let lines = server.join().unwrap().lines;
+        assert!(lines.contains(&String::from("error: Request line too long")));
+        assert!(!lines.iter().any(|l| l.starts_with("info: Request")));
+    }
+
+    #[test]
+    fn pipelined_requests_are_all_served() {
+        let dir = temp_path("pipelined");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        let (mut client, server) = connect();
+
+        let both = format!("LIST {}\nGET {}\n", dir.display(), dir.join("a.txt").display());
+        assert_eq!(send(&mut client, &both, 11 + 6), b"OK 1\na.txt\nOK 1\na");
+        // a request split over two writes is put back together
+        client.write_all(b"PI").unwrap();
+        client.flush().unwrap();
+        thread::sleep(time::Duration::from_millis(20));
+        assert_eq!(send(&mut client, "NG\nQUIT\n", 12 + 9), b"ERR unknown\nOK 1\nBYE\n");
+        server.join().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn request_after_oversized_line_is_kept() {
+        let (mut client, server) = connect();
+        let line = format!("{}\nQUIT\n", "A".repeat(150));
+        assert_eq!(send(&mut client, &line, 18 + 9), b"ERR Path too long\nOK 1\nBYE\n");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn longest_line_still_fits() {
+        let (mut client, server) = connect();
+        // 99 bytes and the newline
+        let line = format!("XX {}\n", "a".repeat(96));
+        assert_eq!(send(&mut client, &line, 12), b"ERR unknown\n");
+        assert_eq!(send(&mut client, "QUIT\n", 9), b"OK 1\nBYE\n");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn requests_are_logged() {
+        let missing = temp_path("logged");
+        let (mut client, server) = connect();
+        send(&mut client, &format!("GET {}\n", missing.display()), 13);
+        send(&mut client, "QUIT\n", 9);
+        let lines = server.join().unwrap().lines;
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("info: New connection 127.0.0.1:"));
+        assert_eq!(lines[1], format!("info: Request \"GET {}\"", missing.display()));
+        assert!(lines[2].starts_with(&format!("error: Error while reading file {:?}: ", missing)));
+        assert_eq!(lines[3], "info: Request \"QUIT\"");
+    }
+}
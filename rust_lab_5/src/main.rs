@@ -1,21 +1,191 @@
-use std::collections::HashMap;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rand::Rng;
 
 type Context = HashMap<&'static str, u64>;
 
-struct Print<T: Expr> {
+// What an expression evaluates to. Where a number is needed a Bool counts as 0 or 1,
+// where a condition is needed Int(0) counts as false.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Int(u64),
+    Bool(bool),
+}
+
+impl Value {
+    fn as_int(self) -> u64 {
+        match self {
+            Value::Int(n) => n,
+            Value::Bool(b) => b as u64,
+        }
+    }
+
+    fn is_true(self) -> bool {
+        match self {
+            Value::Int(n) => n != 0,
+            Value::Bool(b) => b,
+        }
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+// Only an Int equals a number, Bool(true) isn't 1.
+impl PartialEq<u64> for Value {
+    fn eq(&self, other: &u64) -> bool {
+        *self == Value::Int(*other)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+// Names the key given twice to `context_from`.
+#[derive(Debug, PartialEq)]
+struct DuplicateKey(&'static str);
+
+fn context_from(pairs: &[(&'static str, u64)]) -> Result<Context, DuplicateKey> {
+    let mut context = Context::new();
+    for &(name, value) in pairs {
+        if context.insert(name, value).is_some() {
+            return Err(DuplicateKey(name));
+        }
+    }
+    Ok(context)
+}
+
+// Named arrays living next to the context, shared by the statements working on them.
+type VecContext = Rc<RefCell<HashMap<&'static str, Vec<u64>>>>;
+
+// Default output of `Print`, a cloneable handle to stdout.
+#[derive(Clone, Copy)]
+struct Console;
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+// Shared, ordered log of everything printed into it and every `Mark` executed.
+#[derive(Clone, Default)]
+struct ExecutionTrace {
+    entries: Rc<RefCell<Vec<String>>>,
+    pending: Rc<RefCell<String>>,
+}
+
+impl ExecutionTrace {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, entry: &str) {
+        self.entries.borrow_mut().push(entry.to_string());
+    }
+
+    fn entries(&self) -> Vec<String> {
+        self.entries.borrow().clone()
+    }
+}
+
+// Every complete line written becomes one entry.
+impl Write for ExecutionTrace {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pending = self.pending.borrow_mut();
+        pending.push_str(&String::from_utf8_lossy(buf));
+        while let Some(end) = pending.find('\n') {
+            let line: String = pending.drain(..=end).collect();
+            self.record(line.trim_end_matches('\n'));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Print<T: Expr, W: Write = Console> {
     inner: T,
+    out: W,
 }
 
 fn print<T: Expr>(inner: T) -> Print<T> {
-    Print { inner }
+    Print { inner, out: Console }
+}
+
+fn print_to<T: Expr, W: Write>(inner: T, out: W) -> Print<T, W> {
+    Print { inner, out }
+}
+
+impl<T: Expr, W: Write> Stmt for Print<T, W> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        let value = self.inner.exec_expr(context);
+        writeln!(self.out, "{}", value).expect("Unable to write output.");
+    }
+
+    fn label(&self) -> &'static str {
+        "print"
+    }
+
+    // Compiled prints go to the writer given to `run_ops_to`, not to `out`.
+    fn compile_stmt(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.inner.compile_expr(ops)?;
+        ops.push(Op::Print);
+        Ok(())
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.inner.referenced_names(names);
+    }
+}
+
+#[derive(Clone)]
+struct Mark {
+    label: &'static str,
+    trace: ExecutionTrace,
+}
+
+fn mark(label: &'static str, trace: ExecutionTrace) -> Mark {
+    Mark { label, trace }
 }
 
-impl<T: Expr> Stmt for Print<T> {
-    fn exec_stmt(&mut self, context: &Context) {
-        println!("{}", self.inner.exec_expr(context));
+impl Stmt for Mark {
+    fn exec_stmt(&mut self, _context: &mut Context) {
+        self.trace.record(self.label);
+    }
+
+    fn label(&self) -> &'static str {
+        "mark"
     }
 }
 
+#[derive(Clone)]
 struct Nothing;
 
 fn nothing() -> Nothing {
@@ -23,9 +193,18 @@ fn nothing() -> Nothing {
 }
 
 impl Stmt for Nothing {
-    fn exec_stmt(&mut self, _: &Context) {}
+    fn exec_stmt(&mut self, _: &mut Context) {}
+
+    fn label(&self) -> &'static str {
+        "nothing"
+    }
+
+    fn compile_stmt(&self, _ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        Ok(())
+    }
 }
 
+#[derive(Clone)]
 struct Seq<T: Stmt,U: Stmt> {
     first: T,
     second: U,
@@ -36,9 +215,30 @@ fn seq<T: Stmt,U: Stmt>(first: T, second: U) -> Seq<T, U> {
 }
 
 impl<T: Stmt, U: Stmt> Stmt for Seq<T,U> {
-    fn exec_stmt(&mut self, context: &Context) {
+    fn exec_stmt(&mut self, context: &mut Context) {
         self.first.exec_stmt(context);
-        self.second.exec_stmt(context);
+        if !limit_hit() {
+            self.second.exec_stmt(context);
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        "seq"
+    }
+
+    fn atomic_stmts<'s>(&'s mut self, out: &mut Vec<&'s mut dyn Stmt>) {
+        self.first.atomic_stmts(out);
+        self.second.atomic_stmts(out);
+    }
+
+    fn compile_stmt(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.first.compile_stmt(ops)?;
+        self.second.compile_stmt(ops)
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.first.referenced_names(names);
+        self.second.referenced_names(names);
     }
 }
 
@@ -61,11 +261,54 @@ impl Seq<Nothing,Nothing> {
 }
 
 impl Expr for u64 {
-    fn exec_expr(&mut self, _context: &Context) -> u64 {
-        *self
+    fn exec_expr(&mut self, _context: &Context) -> Value {
+        Value::Int(*self)
+    }
+
+    fn literal(&self) -> Option<u64> {
+        Some(*self)
+    }
+
+    fn compile_expr(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        ops.push(Op::PushConst(*self));
+        Ok(())
+    }
+}
+
+// Any expression as a boxed `dyn Expr`, borrowing nodes included.
+pub trait IntoDynExpr {
+    fn into_dyn_expr<'a>(self: Box<Self>) -> Box<dyn Expr + 'a> where Self: 'a;
+}
+
+impl<E: Expr> IntoDynExpr for E {
+    fn into_dyn_expr<'a>(self: Box<Self>) -> Box<dyn Expr + 'a> where Self: 'a {
+        self
+    }
+}
+
+impl Expr for Box<dyn Expr + '_> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        self.as_mut().exec_expr(context)
+    }
+
+    fn literal(&self) -> Option<u64> {
+        self.as_ref().literal()
+    }
+
+    fn simplify<'a>(self: Box<Self>) -> Box<dyn Expr + 'a> where Self: 'a {
+        (*self).simplify()
+    }
+
+    fn compile_expr(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.as_ref().compile_expr(ops)
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.as_ref().referenced_names(names);
     }
 }
 
+#[derive(Clone)]
 struct When<C: Expr, T: Expr, F: Expr> {
     condition: C,
     true_val: T,
@@ -77,132 +320,1846 @@ fn when<C: Expr, T: Expr, F: Expr>(condition: C, true_val: T, false_val: F) -> W
 }
 
 impl<C: Expr, T: Expr, F: Expr > Expr for When<C, T, F> {
-    fn exec_expr(&mut self, context: &Context) -> u64 {
-        let cond = self.condition.exec_expr(context);
-        if cond == 0 {
-            self.false_val.exec_expr(context)
-        } else {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        if self.condition.exec_expr(context).is_true() {
             self.true_val.exec_expr(context)
+        } else {
+            self.false_val.exec_expr(context)
         }
     }
+
+    fn compile_expr(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.condition.compile_expr(ops)?;
+        let to_false = ops.len();
+        ops.push(Op::WhenJump(0));
+        self.true_val.compile_expr(ops)?;
+        let to_end = ops.len();
+        ops.push(Op::Jump(0));
+        ops[to_false] = Op::WhenJump(ops.len());
+        self.false_val.compile_expr(ops)?;
+        ops[to_end] = Op::Jump(ops.len());
+        Ok(())
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.condition.referenced_names(names);
+        self.true_val.referenced_names(names);
+        self.false_val.referenced_names(names);
+    }
+
+    fn simplify<'a>(self: Box<Self>) -> Box<dyn Expr + 'a> where Self: 'a {
+        let When {condition, true_val, false_val} = *self;
+        let (t, f) = (Box::new(true_val).simplify(), Box::new(false_val).simplify());
+        when(Box::new(condition).simplify(), t, f).fold()
+    }
 }
 
-struct Repeat<const N: u32, T: Stmt> {
-    inner: T,
+impl<C: Expr, T: Expr, F: Expr> When<C, T, F> {
+    // A literal condition picks the branch now, so it is never evaluated at runtime.
+    fn fold<'a>(self) -> Box<dyn Expr + 'a> where Self: 'a {
+        match self.condition.literal() {
+            Some(0) => Box::new(self.false_val),
+            Some(_) => Box::new(self.true_val),
+            None => Box::new(self),
+        }
+    }
 }
 
-fn repeat<const N: u32, T: Stmt>(inner: T) -> Repeat<N, T> {
-    Repeat {inner}
+// Out-of-range selectors are clamped to the last option, no options at all evaluate to 0.
+struct Choose<S: Expr, O: Expr = Box<dyn Expr>> {
+    selector: S,
+    options: Vec<O>,
 }
 
-impl<const N: u32, T: Stmt> Stmt for Repeat<N, T> {
-    fn exec_stmt(&mut self, context: &Context) {
-        for _ in 0..N {
-            self.inner.exec_stmt(context);
+fn choose<S: Expr>(selector: S, options: Vec<Box<dyn Expr>>) -> Choose<S> {
+    Choose {selector, options}
+}
+
+// Choose whose options can be evaluated with `try_exec_expr`.
+fn try_choose<S: TryExpr>(selector: S, options: Vec<Box<dyn TryExpr>>)
+    -> Choose<S, Box<dyn TryExpr>> {
+    Choose {selector, options}
+}
+
+impl<S: Expr, O: Expr> Choose<S, O> {
+    fn option(&mut self, selector: Value) -> Option<&mut O> {
+        let last = self.options.len().saturating_sub(1);
+        let index = usize::try_from(selector.as_int()).map_or(last, |i| i.min(last));
+        self.options.get_mut(index)
+    }
+}
+
+impl<S: Expr, O: Expr> Expr for Choose<S, O> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let selector = self.selector.exec_expr(context);
+        self.option(selector).map_or(Value::Int(0), |option| option.exec_expr(context))
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.selector.referenced_names(names);
+        for option in &self.options {
+            option.referenced_names(names);
         }
     }
 }
 
-struct Constant {
-    name: &'static str,
+// Division by zero evaluates to 0 instead of aborting the whole program.
+#[derive(Clone)]
+struct Mod<A: Expr, B: Expr> {
+    a: A,
+    b: B,
 }
 
-fn constant(name: &'static str) -> Constant {
-    Constant {name}
+fn modulo<A: Expr, B: Expr>(a: A, b: B) -> Mod<A, B> {
+    Mod {a, b}
 }
 
-impl Expr for Constant {
-    fn exec_expr(&mut self, context: &Context) -> u64 {
-        *context.get(self.name).unwrap_or_else(|| panic!("{} not found", self.name))
+impl<A: Expr, B: Expr> Expr for Mod<A, B> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let a = self.a.exec_expr(context).as_int();
+        Value::Int(a.checked_rem(self.b.exec_expr(context).as_int()).unwrap_or(0))
+    }
+
+    fn compile_expr(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.a.compile_expr(ops)?;
+        self.b.compile_expr(ops)?;
+        ops.push(Op::Mod);
+        Ok(())
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.a.referenced_names(names);
+        self.b.referenced_names(names);
+    }
+
+    fn simplify<'a>(self: Box<Self>) -> Box<dyn Expr + 'a> where Self: 'a {
+        let Mod {a, b} = *self;
+        simplify_operands(Box::new(a), Box::new(b), modulo)
     }
 }
 
-struct ReadFrom<'a> {
-    name: &'a u64,
+// Division by zero evaluates to 0, same as `Mod`.
+#[derive(Clone)]
+struct IDiv<A: Expr, B: Expr> {
+    a: A,
+    b: B,
 }
 
-fn read_from<'a>(name: &'a u64) -> ReadFrom<'a> {
-    ReadFrom{name}
+fn idiv<A: Expr, B: Expr>(a: A, b: B) -> IDiv<A, B> {
+    IDiv {a, b}
 }
 
-impl<'a> Expr for ReadFrom<'a> {
-    fn exec_expr(&mut self, _context: &Context) -> u64 {
-        *self.name
+impl<A: Expr, B: Expr> Expr for IDiv<A, B> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let a = self.a.exec_expr(context).as_int();
+        Value::Int(a.checked_div(self.b.exec_expr(context).as_int()).unwrap_or(0))
+    }
+
+    fn compile_expr(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.a.compile_expr(ops)?;
+        self.b.compile_expr(ops)?;
+        ops.push(Op::IDiv);
+        Ok(())
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.a.referenced_names(names);
+        self.b.referenced_names(names);
+    }
+
+    fn simplify<'a>(self: Box<Self>) -> Box<dyn Expr + 'a> where Self: 'a {
+        let IDiv {a, b} = *self;
+        simplify_operands(Box::new(a), Box::new(b), idiv)
     }
 }
 
-struct SaveIn<'a, T: Expr> {
-    destination: &'a mut u64,
-    inner: T
+// Overflow saturates at u64::MAX rather than panicking, like division by zero in `Mod`.
+#[derive(Clone)]
+struct CheckedAdd<A: Expr, B: Expr> {
+    a: A,
+    b: B,
 }
 
-fn save_in<'a, T: Expr>(destination: &'a mut u64, inner: T) -> SaveIn<'a, T> {
-    SaveIn{destination, inner}
+fn checked_add<A: Expr, B: Expr>(a: A, b: B) -> CheckedAdd<A, B> {
+    CheckedAdd {a, b}
 }
 
-impl<'a, T: Expr> Expr for SaveIn<'a, T> {
-    fn exec_expr(&mut self, context: &Context) -> u64 {
+impl<A: Expr, B: Expr> Expr for CheckedAdd<A, B> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let a = self.a.exec_expr(context).as_int();
+        Value::Int(a.saturating_add(self.b.exec_expr(context).as_int()))
+    }
+
+    fn compile_expr(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.a.compile_expr(ops)?;
+        self.b.compile_expr(ops)?;
+        ops.push(Op::Add);
+        Ok(())
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.a.referenced_names(names);
+        self.b.referenced_names(names);
+    }
+
+    fn simplify<'a>(self: Box<Self>) -> Box<dyn Expr + 'a> where Self: 'a {
+        let CheckedAdd {a, b} = *self;
+        simplify_operands(Box::new(a), Box::new(b), checked_add)
+    }
+}
+
+// Saturates the same way as `CheckedAdd`.
+#[derive(Clone)]
+struct CheckedMul<A: Expr, B: Expr> {
+    a: A,
+    b: B,
+}
+
+fn checked_mul<A: Expr, B: Expr>(a: A, b: B) -> CheckedMul<A, B> {
+    CheckedMul {a, b}
+}
+
+impl<A: Expr, B: Expr> Expr for CheckedMul<A, B> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let a = self.a.exec_expr(context).as_int();
+        Value::Int(a.saturating_mul(self.b.exec_expr(context).as_int()))
+    }
+
+    fn compile_expr(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.a.compile_expr(ops)?;
+        self.b.compile_expr(ops)?;
+        ops.push(Op::Mul);
+        Ok(())
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.a.referenced_names(names);
+        self.b.referenced_names(names);
+    }
+
+    fn simplify<'a>(self: Box<Self>) -> Box<dyn Expr + 'a> where Self: 'a {
+        let CheckedMul {a, b} = *self;
+        simplify_operands(Box::new(a), Box::new(b), checked_mul)
+    }
+}
+
+// Bool(a < b), both sides compared as numbers.
+#[derive(Clone)]
+struct Less<A: Expr, B: Expr> {
+    a: A,
+    b: B,
+}
+
+fn less<A: Expr, B: Expr>(a: A, b: B) -> Less<A, B> {
+    Less {a, b}
+}
+
+impl<A: Expr, B: Expr> Expr for Less<A, B> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let a = self.a.exec_expr(context).as_int();
+        Value::Bool(a < self.b.exec_expr(context).as_int())
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.a.referenced_names(names);
+        self.b.referenced_names(names);
+    }
+}
+
+// Passes the value of `inner` through, writing it down under `label` on the way.
+#[derive(Clone)]
+struct Probe<T: Expr> {
+    label: &'static str,
+    log: Rc<RefCell<Vec<(&'static str, Value)>>>,
+    inner: T,
+}
+
+fn probe<T: Expr>(label: &'static str, log: Rc<RefCell<Vec<(&'static str, Value)>>>, inner: T)
+    -> Probe<T> {
+    Probe {label, log, inner}
+}
+
+impl<T: Expr> Expr for Probe<T> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
         let value = self.inner.exec_expr(context);
-        *self.destination = value;
+        self.log.borrow_mut().push((self.label, value));
         value
     }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.inner.referenced_names(names);
+    }
 }
 
-struct Volatile<'a, T: Expr> {
-    destination: &'a mut u64,
-    name: &'static str,
+#[derive(Clone)]
+struct Repeat<const N: u32, T: Stmt> {
     inner: T,
 }
 
-fn volatile<'a, T: Expr>(destination: &'a mut u64, name: &'static str, inner: T)
-    -> Volatile<'a, T> {
-    Volatile{destination, name, inner}
+fn repeat<const N: u32, T: Stmt>(inner: T) -> Repeat<N, T> {
+    Repeat {inner}
 }
 
-impl<'a, T: Expr> Expr for Volatile<'a, T> {
-    fn exec_expr(&mut self, context: &Context) -> u64 {
-        let mut new_context = context.clone();
-        new_context.insert(self.name, *self.destination);
-        let value = self.inner.exec_expr(&new_context);
-        *self.destination = value;
-        value
+impl<const N: u32, T: Stmt> Stmt for Repeat<N, T> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        run_loop(context, |index, _| index < N as u64, |context| self.inner.exec_stmt(context));
+    }
+
+    fn label(&self) -> &'static str {
+        "repeat"
+    }
+
+    fn compile_stmt(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        compile_repeat(N, &self.inner, ops)
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.inner.referenced_names(names);
     }
 }
 
-pub trait Expr {
-    fn exec_expr(&mut self, context: &Context) -> u64;
+// Same as `Repeat`, but with the count known only at runtime, e.g. when parsed.
+#[derive(Clone)]
+struct RepeatN<T: Stmt> {
+    times: u32,
+    inner: T,
 }
 
-pub trait Stmt {
-    fn exec_stmt(&mut self, context: &Context);
+fn repeat_n<T: Stmt>(times: u32, inner: T) -> RepeatN<T> {
+    RepeatN {times, inner}
 }
 
-fn main() {
-    let context = HashMap::from([("x", 0), ("y", 10)]);
+impl<T: Stmt> Stmt for RepeatN<T> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        let times = self.times as u64;
+        run_loop(context, |index, _| index < times, |context| self.inner.exec_stmt(context));
+    }
 
-    let mut program = seq(
-        print(when(constant("x"), 1u64, 2u64)),
-        print(when(constant("y"), 1u64, 2u64))
-    );
-    program.exec_stmt(&context);
+    fn label(&self) -> &'static str {
+        "repeat"
+    }
 
-    let seq1 = seq(print(1u64), nothing());
-    let mut s1 = seq1.shorten_1();
-    s1.exec_stmt(&context);
+    fn compile_stmt(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        compile_repeat(self.times, &self.inner, ops)
+    }
 
-    let seq2 = seq(nothing(), print(2u64));
-    let mut s2 = seq2.shorten_2();
-    s2.exec_stmt(&context);
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.inner.referenced_names(names);
+    }
+}
+
+#[derive(Clone)]
+struct While<C: Expr, B: Stmt> {
+    cond: C,
+    body: B,
+}
+
+fn while_loop<C: Expr, B: Stmt>(cond: C, body: B) -> While<C, B> {
+    While {cond, body}
+}
+
+impl<C: Expr, B: Stmt> Stmt for While<C, B> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        // Unlimited, an enclosing WithLimit is what stops a runaway loop.
+        let _ = run_while_limited(&mut self.cond, &mut self.body, context, u64::MAX);
+    }
+
+    fn label(&self) -> &'static str {
+        "while"
+    }
+
+    fn compile_stmt(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        let start = ops.len();
+        self.cond.compile_expr(ops)?;
+        let to_end = ops.len();
+        ops.push(Op::WhenJump(0));
+        self.body.compile_stmt(ops)?;
+        ops.push(Op::Jump(start));
+        ops[to_end] = Op::WhenJump(ops.len());
+        Ok(())
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.cond.referenced_names(names);
+        self.body.referenced_names(names);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct LoopLimitExceeded;
+
+// Runs `body` while `cond` is nonzero and returns how many times it ran. Fails when `cond` is
+// still nonzero after `max` iterations or when an enclosing WithLimit runs out of steps.
+fn run_while_limited(cond: &mut dyn Expr, body: &mut dyn Stmt, context: &mut Context, max: u64)
+    -> Result<u64, LoopLimitExceeded> {
+    let mut result = Err(LoopLimitExceeded);
+    run_loop(context, |index, context| {
+        if !cond.exec_expr(context).is_true() {
+            result = Ok(index);
+            return false;
+        }
+        index < max
+    }, |context| body.exec_stmt(context));
+    result
+}
+
+// Runs `body` at most `max` times, `cond` is checked after each run and nonzero stops the loop.
+#[derive(Clone)]
+struct RepeatUntil<C: Expr, B: Stmt> {
+    max: u32,
+    cond: C,
+    body: B,
+}
+
+fn repeat_until<C: Expr, B: Stmt>(max: u32, cond: C, body: B) -> RepeatUntil<C, B> {
+    RepeatUntil {max, cond, body}
+}
+
+impl<C: Expr, B: Stmt> Stmt for RepeatUntil<C, B> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        let done = Cell::new(false);
+        run_loop(context, |index, _| index < self.max as u64 && !done.get(), |context| {
+            self.body.exec_stmt(context);
+            done.set(self.cond.exec_expr(context).is_true());
+        });
+    }
+
+    fn label(&self) -> &'static str {
+        "repeat_until"
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.cond.referenced_names(names);
+        self.body.referenced_names(names);
+    }
+}
+
+thread_local! {
+    // Remaining iterations of the innermost `WithLimit`, None when running unlimited. Kept out of
+    // the context, so a program can neither read nor overwrite it.
+    static STEPS_LEFT: Cell<Option<u64>> = const { Cell::new(None) };
+    // Set once a loop asked for a step the budget didn't have, the rest of the limited body is
+    // skipped from then on.
+    static LIMIT_HIT: Cell<bool> = const { Cell::new(false) };
+    // Zero-based iteration of every running loop, the innermost last. Read by `LoopIndex`.
+    static LOOP_INDICES: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// Step hook called by loops before every iteration, false once the budget is used up.
+fn take_step() -> bool {
+    match STEPS_LEFT.get() {
+        Some(0) => {
+            LIMIT_HIT.set(true);
+            false
+        }
+        Some(left) => {
+            STEPS_LEFT.set(Some(left - 1));
+            true
+        }
+        None => true,
+    }
+}
+
+// True while the innermost WithLimit is aborting, Seq and Block check it between statements.
+fn limit_hit() -> bool {
+    LIMIT_HIT.get()
+}
+
+// Puts the enclosing budget back, charged for the iterations used, also when the limited body
+// panics. Running out of the enclosing budget aborts the enclosing body as well.
+struct StepBudget {
+    outer: Option<u64>,
+    budget: u64,
+}
+
+impl Drop for StepBudget {
+    fn drop(&mut self) {
+        let left = STEPS_LEFT.get().unwrap_or(0).min(self.budget);
+        let outer_left = self.outer.map(|outer| outer - (self.budget - left));
+        STEPS_LEFT.set(outer_left);
+        LIMIT_HIT.set(LIMIT_HIT.get() && outer_left == Some(0));
+    }
+}
+
+// Runs `run` with at most `max_steps` loop iterations, an enclosing budget still applies and is
+// charged for the iterations used here.
+fn with_step_budget<T>(max_steps: u64, run: impl FnOnce() -> T) -> T {
+    let outer = STEPS_LEFT.get();
+    let budget = outer.map_or(max_steps, |left| left.min(max_steps));
+    STEPS_LEFT.set(Some(budget));
+    let _restore = StepBudget {outer, budget};
+    run()
+}
+
+// Pops the index of the loop it was made for, also when the body panics.
+struct LoopIndexGuard;
+
+impl Drop for LoopIndexGuard {
+    fn drop(&mut self) {
+        LOOP_INDICES.with_borrow_mut(|indices| indices.pop());
+    }
+}
+
+// Shared driver of all loops: the index is set before `keep_going` is asked, every iteration
+// takes a step, and the enclosing loop's index is back in place once this one ends.
+fn run_loop(context: &mut Context, mut keep_going: impl FnMut(u64, &Context) -> bool,
+            mut body: impl FnMut(&mut Context)) {
+    LOOP_INDICES.with_borrow_mut(|indices| indices.push(0));
+    let _pop = LoopIndexGuard;
+    let mut index = 0;
+    loop {
+        LOOP_INDICES.with_borrow_mut(|indices| *indices.last_mut().expect("pushed above") = index);
+        if !keep_going(index, context) || !take_step() {
+            break;
+        }
+        body(context);
+        index += 1;
+    }
+}
+
+// Seconds since the Unix epoch, of the injected `at` if there is one, of the real clock otherwise.
+// Times before the epoch give 0.
+#[derive(Clone)]
+struct Now {
+    at: Option<SystemTime>,
+}
+
+fn now() -> Now {
+    Now {at: None}
+}
+
+fn now_at(at: SystemTime) -> Now {
+    Now {at: Some(at)}
+}
+
+impl Expr for Now {
+    fn exec_expr(&mut self, _context: &Context) -> Value {
+        let at = self.at.unwrap_or_else(SystemTime::now);
+        Value::Int(at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()))
+    }
+}
+
+// Outside of any loop the index is 0.
+#[derive(Clone)]
+struct LoopIndex;
+
+fn loop_index() -> LoopIndex {
+    LoopIndex
+}
+
+impl Expr for LoopIndex {
+    fn exec_expr(&mut self, _context: &Context) -> Value {
+        Value::Int(LOOP_INDICES.with_borrow(|indices| indices.last().copied().unwrap_or(0)))
+    }
+}
+
+// Runs `body` with at most `max_steps` loop iterations, an enclosing limit still applies
+// and is charged for the iterations used here. Once a loop runs out of steps it stops and so does
+// the rest of `body`.
+#[derive(Clone)]
+struct WithLimit<B: Stmt> {
+    max_steps: u64,
+    body: B,
+}
+
+fn with_limit<B: Stmt>(max_steps: u64, body: B) -> WithLimit<B> {
+    WithLimit {max_steps, body}
+}
+
+impl<B: Stmt> Stmt for WithLimit<B> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        with_step_budget(self.max_steps, || self.body.exec_stmt(context));
+    }
+
+    fn label(&self) -> &'static str {
+        "with_limit"
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.body.referenced_names(names);
+    }
+}
+
+#[derive(Clone)]
+struct Constant {
+    name: &'static str,
+}
+
+fn constant(name: &'static str) -> Constant {
+    Constant {name}
+}
+
+impl Expr for Constant {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        Value::Int(*context.get(self.name).unwrap_or_else(|| panic!("{} not found", self.name)))
+    }
+
+    fn compile_expr(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        ops.push(Op::LoadVar(self.name));
+        Ok(())
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        names.push(self.name);
+    }
+}
+
+#[derive(Clone)]
+struct Assert<C: Expr> {
+    cond: C,
+    msg: &'static str,
+}
+
+fn assert<C: Expr>(cond: C, msg: &'static str) -> Assert<C> {
+    Assert {cond, msg}
+}
+
+impl<C: Expr> Stmt for Assert<C> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        if !self.cond.exec_expr(context).is_true() {
+            panic!("{}", self.msg);
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        "assert"
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.cond.referenced_names(names);
+    }
+}
+
+// Evaluates `inner` once and keeps answering with that value, even after the context
+// has changed. A clone made after the first evaluation shares the answer.
+#[derive(Clone)]
+struct Memo<T: Expr> {
+    cache: OnceCell<Value>,
+    inner: T,
+}
+
+fn memo<T: Expr>(inner: T) -> Memo<T> {
+    Memo {cache: OnceCell::new(), inner}
+}
+
+impl<T: Expr> Expr for Memo<T> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        *self.cache.get_or_init(|| self.inner.exec_expr(context))
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.inner.referenced_names(names);
+    }
+}
+
+// Feeds the value of `input` through `stages`, left to right.
+struct Pipe<T: Expr> {
+    stages: Vec<Box<dyn Fn(u64) -> u64>>,
+    input: T,
+}
+
+fn pipe<T: Expr>(input: T, stages: Vec<Box<dyn Fn(u64) -> u64>>) -> Pipe<T> {
+    Pipe {stages, input}
+}
+
+impl<T: Expr> Pipe<T> {
+    fn run_stages(&self, value: u64) -> Value {
+        Value::Int(self.stages.iter().fold(value, |value, stage| stage(value)))
+    }
+}
+
+impl<T: Expr> Expr for Pipe<T> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let value = self.input.exec_expr(context).as_int();
+        self.run_stages(value)
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.input.referenced_names(names);
+    }
+}
+
+// A clone made after the first run is already done as well.
+#[derive(Clone)]
+struct RunOnce<B: Stmt> {
+    done: Cell<bool>,
+    body: B,
+}
+
+fn run_once<B: Stmt>(body: B) -> RunOnce<B> {
+    RunOnce {done: Cell::new(false), body}
+}
+
+impl<B: Stmt> Stmt for RunOnce<B> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        if !self.done.replace(true) {
+            self.body.exec_stmt(context);
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        "run_once"
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.body.referenced_names(names);
+    }
+}
+
+#[derive(Clone)]
+struct Assign<T: Expr> {
+    name: &'static str,
+    value: T,
+}
+
+fn assign<T: Expr>(name: &'static str, value: T) -> Assign<T> {
+    Assign {name, value}
+}
+
+impl<T: Expr> Stmt for Assign<T> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        let value = self.value.exec_expr(context).as_int();
+        context.insert(self.name, value);
+    }
+
+    fn label(&self) -> &'static str {
+        "assign"
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.value.referenced_names(names);
+    }
+}
+
+// Keys changed or added by `stmt` as (key, old, new), sorted by key. Removed keys aren't listed.
+fn run_and_diff(stmt: &mut dyn Stmt, context: &mut Context)
+    -> Vec<(&'static str, Option<u64>, u64)> {
+    let before = context.clone();
+    stmt.exec_stmt(context);
+    let mut diff: Vec<_> = context.iter()
+        .map(|(&key, &new)| (key, before.get(key).copied(), new))
+        .filter(|&(_, old, new)| old != Some(new))
+        .collect();
+    diff.sort();
+    diff
+}
+
+// Context keys no `Constant` in `stmt` reads, sorted, e.g. misspelled names. Known without
+// running the program, so branches that would never be taken still count as reading.
+fn unused_keys(stmt: &dyn Stmt, context: &Context) -> Vec<&'static str> {
+    let mut names = Vec::new();
+    stmt.referenced_names(&mut names);
+    let mut unused: Vec<_> = context.keys().copied().filter(|key| !names.contains(key)).collect();
+    unused.sort();
+    unused
+}
+
+// Appends how long `body` took under `label` to the shared `out`.
+#[derive(Clone)]
+struct Timed<B: Stmt> {
+    label: &'static str,
+    out: Rc<RefCell<Vec<(&'static str, Duration)>>>,
+    body: B,
+}
+
+fn timed<B: Stmt>(label: &'static str, out: Rc<RefCell<Vec<(&'static str, Duration)>>>, body: B)
+    -> Timed<B> {
+    Timed {label, out, body}
+}
+
+impl<B: Stmt> Stmt for Timed<B> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        let now = Instant::now();
+        self.body.exec_stmt(context);
+        self.out.borrow_mut().push((self.label, now.elapsed()));
+    }
+
+    fn label(&self) -> &'static str {
+        "timed"
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.body.referenced_names(names);
+    }
+}
+
+// Keys the loops keep in the context for their own bookkeeping, never shown to the user.
+const RESERVED_KEYS: [&str; 2] = [ELEMENT, ACCUMULATOR];
+
+// Appends every (key, value) of the context, sorted by key, to the shared `out`. Reserved keys
+// are left out.
+#[derive(Clone)]
+struct DumpContext {
+    out: Rc<RefCell<Vec<(String, u64)>>>,
+}
+
+fn dump_context(out: Rc<RefCell<Vec<(String, u64)>>>) -> DumpContext {
+    DumpContext {out}
+}
+
+impl Stmt for DumpContext {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        let mut pairs: Vec<_> = context.iter()
+            .filter(|(k, _)| !RESERVED_KEYS.contains(k))
+            .map(|(&k, &v)| (k.to_string(), v))
+            .collect();
+        pairs.sort();
+        self.out.borrow_mut().extend(pairs);
+    }
+
+    fn label(&self) -> &'static str {
+        "dump_context"
+    }
+}
+
+// Overrides are visible only inside `body`, keys added by the scope are removed on exit.
+#[derive(Clone)]
+struct Scope<B: Stmt> {
+    overrides: Vec<(&'static str, u64)>,
+    body: B,
+}
+
+fn scope<B: Stmt>(overrides: Vec<(&'static str, u64)>, body: B) -> Scope<B> {
+    Scope {overrides, body}
+}
+
+impl<B: Stmt> Stmt for Scope<B> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        let previous: Vec<(&'static str, Option<u64>)> = self.overrides.iter()
+            .map(|&(name, value)| (name, context.insert(name, value)))
+            .collect();
+        self.body.exec_stmt(context);
+        for (name, value) in previous.into_iter().rev() {
+            match value {
+                Some(value) => context.insert(name, value),
+                None => context.remove(name),
+            };
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        "scope"
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.body.referenced_names(names);
+    }
+}
+
+// Element of the array currently being transformed by `MapInPlace` or folded by `Fold`.
+const ELEMENT: &str = "__element";
+
+fn element() -> Constant {
+    constant(ELEMENT)
+}
+
+// Replaces every element of `slot` with `transform` evaluated while the element is bound to
+// `ELEMENT`, a missing slot is left alone.
+#[derive(Clone)]
+struct MapInPlace<T: Expr> {
+    arrays: VecContext,
+    slot: &'static str,
+    transform: T,
+}
+
+fn map_in_place<T: Expr>(arrays: VecContext, slot: &'static str, transform: T) -> MapInPlace<T> {
+    MapInPlace {arrays, slot, transform}
+}
+
+impl<T: Expr> Stmt for MapInPlace<T> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        let Some(mut values) = self.arrays.borrow().get(self.slot).cloned() else {
+            return;
+        };
+        let outer = context.get(ELEMENT).copied();
+        for value in values.iter_mut() {
+            context.insert(ELEMENT, *value);
+            *value = self.transform.exec_expr(context).as_int();
+        }
+        match outer {
+            Some(outer) => context.insert(ELEMENT, outer),
+            None => context.remove(ELEMENT),
+        };
+        self.arrays.borrow_mut().insert(self.slot, values);
+    }
+
+    fn label(&self) -> &'static str {
+        "map_in_place"
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.transform.referenced_names(names);
+    }
+}
+
+// Value folded so far by the innermost `Fold`.
+const ACCUMULATOR: &str = "__acc";
+
+fn accumulator() -> Constant {
+    constant(ACCUMULATOR)
+}
+
+// Starts from `init` and replaces the accumulator with `combine` for every element of `slot`,
+// evaluated with `ACCUMULATOR` and `ELEMENT` bound. A missing slot folds to `init`.
+#[derive(Clone)]
+struct Fold<T: Expr> {
+    arrays: VecContext,
+    slot: &'static str,
+    init: u64,
+    combine: T,
+}
+
+fn fold<T: Expr>(arrays: VecContext, slot: &'static str, init: u64, combine: T) -> Fold<T> {
+    Fold {arrays, slot, init, combine}
+}
+
+impl<T: Expr> Expr for Fold<T> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let values = self.arrays.borrow().get(self.slot).cloned().unwrap_or_default();
+        let mut inner = context.clone();
+        let acc = values.into_iter().fold(self.init, |acc, value| {
+            inner.insert(ACCUMULATOR, acc);
+            inner.insert(ELEMENT, value);
+            self.combine.exec_expr(&inner).as_int()
+        });
+        Value::Int(acc)
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.combine.referenced_names(names);
+    }
+}
+
+impl Stmt for Box<dyn Stmt + '_> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        self.as_mut().exec_stmt(context);
+    }
+
+    fn label(&self) -> &'static str {
+        self.as_ref().label()
+    }
+
+    fn atomic_stmts<'s>(&'s mut self, out: &mut Vec<&'s mut dyn Stmt>) {
+        self.as_mut().atomic_stmts(out);
+    }
+
+    fn compile_stmt(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.as_ref().compile_stmt(ops)
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.as_ref().referenced_names(names);
+    }
+}
+
+// A flat list of statements, the form a program has to be in to be stepped through.
+struct Block<S: Stmt = Box<dyn Stmt>> {
+    stmts: Vec<S>,
+}
+
+fn block(stmts: Vec<Box<dyn Stmt>>) -> Block {
+    Block {stmts}
+}
+
+// Block whose statements can be run with `try_exec_stmt`.
+fn try_block(stmts: Vec<Box<dyn TryStmt>>) -> Block<Box<dyn TryStmt>> {
+    Block {stmts}
+}
+
+impl<S: Stmt> Stmt for Block<S> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        for stmt in &mut self.stmts {
+            if limit_hit() {
+                break;
+            }
+            stmt.exec_stmt(context);
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        "block"
+    }
+
+    fn atomic_stmts<'s>(&'s mut self, out: &mut Vec<&'s mut dyn Stmt>) {
+        for stmt in &mut self.stmts {
+            stmt.atomic_stmts(out);
+        }
+    }
+
+    fn compile_stmt(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.stmts.iter().try_for_each(|stmt| stmt.compile_stmt(ops))
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        for stmt in &self.stmts {
+            stmt.referenced_names(names);
+        }
+    }
+}
+
+impl<S: Stmt> Block<S> {
+    fn steps(&mut self, context: Context) -> Stepper<'_> {
+        let mut stmts = Vec::new();
+        self.atomic_stmts(&mut stmts);
+        Stepper {stmts: stmts.into_iter(), about_to_run: None, index: 0, context}
+    }
+}
+
+#[derive(Debug)]
+struct StepInfo {
+    index: usize,
+    label: &'static str,
+    context: Context,
+}
+
+// Each `next` describes the statement about to run together with the context it will run
+// against. It only runs once `next` is called again, so a caller can stop before it.
+struct Stepper<'p> {
+    stmts: std::vec::IntoIter<&'p mut dyn Stmt>,
+    about_to_run: Option<&'p mut dyn Stmt>,
+    index: usize,
+    context: Context,
+}
+
+impl Iterator for Stepper<'_> {
+    type Item = StepInfo;
+
+    fn next(&mut self) -> Option<StepInfo> {
+        if let Some(stmt) = self.about_to_run.take() {
+            stmt.exec_stmt(&mut self.context);
+            self.index += 1;
+        }
+        let stmt = self.stmts.next()?;
+        let info = StepInfo {index: self.index, label: stmt.label(), context: self.context.clone()};
+        self.about_to_run = Some(stmt);
+        Some(info)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseError {
+    UnexpectedEnd,
+    UnexpectedToken(String),
+    BadNumber(String),
+}
+
+thread_local! {
+    static NAMES: RefCell<HashSet<&'static str>> = RefCell::default();
+}
+
+// Context keys are 'static, so a name read from a program is leaked once and shared afterwards.
+fn intern(name: &str) -> &'static str {
+    NAMES.with_borrow_mut(|names| match names.get(name) {
+        Some(&interned) => interned,
+        None => {
+            let interned: &'static str = Box::leak(name.into());
+            names.insert(interned);
+            interned
+        }
+    })
+}
+
+fn parse_program(src: &str) -> Result<Box<dyn Stmt>, ParseError> {
+    parse_program_with(src, Console)
+}
+
+// Every `print` in the program writes to its own clone of `out`.
+fn parse_program_with<W: Write + Clone + 'static>(src: &str, out: W)
+    -> Result<Box<dyn Stmt>, ParseError> {
+    let mut parser = Parser {tokens: tokenize(src).into_iter().peekable(), out};
+    let stmts = parser.parse_stmts()?;
+    match parser.tokens.next() {
+        Some(token) => Err(ParseError::UnexpectedToken(token.to_string())),
+        None => Ok(Box::new(block(stmts))),
+    }
+}
+
+// Words are runs of alphanumerics and '_', punctuation is one token per character.
+fn tokenize(src: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut word_start = None;
+    for (i, c) in src.char_indices().chain([(src.len(), ' ')]) {
+        let is_word = c.is_alphanumeric() || c == '_';
+        if let Some(start) = word_start && !is_word {
+            tokens.push(&src[start..i]);
+            word_start = None;
+        }
+        if is_word && word_start.is_none() {
+            word_start = Some(i);
+        } else if !is_word && !c.is_whitespace() {
+            tokens.push(&src[i..i + c.len_utf8()]);
+        }
+    }
+    tokens
+}
+
+struct Parser<'a, W> {
+    tokens: std::iter::Peekable<std::vec::IntoIter<&'a str>>,
+    out: W,
+}
+
+impl<'a, W: Write + Clone + 'static> Parser<'a, W> {
+    fn next(&mut self) -> Result<&'a str, ParseError> {
+        self.tokens.next().ok_or(ParseError::UnexpectedEnd)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), ParseError> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(ParseError::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    // Statements up to a closing brace or the end, separated by ';'. A statement ending in a
+    // block needs no ';' after its '}'.
+    fn parse_stmts(&mut self) -> Result<Vec<Box<dyn Stmt>>, ParseError> {
+        let mut stmts = Vec::new();
+        loop {
+            match self.tokens.peek() {
+                None | Some(&"}") => return Ok(stmts),
+                Some(&";") => {
+                    self.tokens.next();
+                }
+                Some(&first) => {
+                    let ends_in_block = first == "repeat";
+                    stmts.push(self.parse_stmt()?);
+                    match self.tokens.peek() {
+                        None | Some(&";") | Some(&"}") => {}
+                        Some(_) if ends_in_block => {}
+                        Some(&token) => return Err(ParseError::UnexpectedToken(token.to_string())),
+                    }
+                }
+            }
+        }
+    }
+
+    // Names start with a letter or '_', tokenize already keeps them to alphanumerics and '_'.
+    fn name(&mut self) -> Result<&'a str, ParseError> {
+        match self.next()? {
+            token if token.starts_with(|c: char| c.is_alphabetic() || c == '_') => Ok(token),
+            token => Err(ParseError::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    fn parse_stmt(&mut self) -> Result<Box<dyn Stmt>, ParseError> {
+        match self.next()? {
+            "print" => Ok(Box::new(print_to(self.parse_expr()?, self.out.clone()))),
+            "nothing" => Ok(Box::new(nothing())),
+            "repeat" => {
+                let count = self.next()?;
+                let times = count.parse().map_err(|_| ParseError::BadNumber(count.to_string()))?;
+                self.expect("{")?;
+                let body = self.parse_stmts()?;
+                self.expect("}")?;
+                Ok(Box::new(repeat_n(times, block(body))))
+            }
+            token => Err(ParseError::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<Box<dyn Expr>, ParseError> {
+        match self.next()? {
+            "constant" => {
+                self.expect("(")?;
+                let name = self.name()?;
+                self.expect(")")?;
+                Ok(Box::new(constant(intern(name))))
+            }
+            "when" => {
+                self.expect("(")?;
+                let condition = self.parse_expr()?;
+                self.expect(",")?;
+                let true_val = self.parse_expr()?;
+                self.expect(",")?;
+                let false_val = self.parse_expr()?;
+                self.expect(")")?;
+                Ok(Box::new(when(condition, true_val, false_val)))
+            }
+            token if token.starts_with(|c: char| c.is_ascii_digit()) =>
+                token.parse::<u64>().map(|n| Box::new(n) as Box<dyn Expr>)
+                    .map_err(|_| ParseError::BadNumber(token.to_string())),
+            token => Err(ParseError::UnexpectedToken(token.to_string())),
+        }
+    }
+}
+
+struct ReadFrom<'a> {
+    name: &'a u64,
+}
+
+fn read_from<'a>(name: &'a u64) -> ReadFrom<'a> {
+    ReadFrom{name}
+}
+
+impl<'a> Expr for ReadFrom<'a> {
+    fn exec_expr(&mut self, _context: &Context) -> Value {
+        Value::Int(*self.name)
+    }
+}
+
+struct SaveIn<'a, T: Expr> {
+    destination: &'a mut u64,
+    inner: T
+}
+
+fn save_in<'a, T: Expr>(destination: &'a mut u64, inner: T) -> SaveIn<'a, T> {
+    SaveIn{destination, inner}
+}
+
+impl<'a, T: Expr> Expr for SaveIn<'a, T> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let value = self.inner.exec_expr(context);
+        *self.destination = value.as_int();
+        value
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.inner.referenced_names(names);
+    }
+}
+
+struct Volatile<'a, T: Expr> {
+    destination: &'a mut u64,
+    name: &'static str,
+    inner: T,
+}
+
+fn volatile<'a, T: Expr>(destination: &'a mut u64, name: &'static str, inner: T)
+    -> Volatile<'a, T> {
+    Volatile{destination, name, inner}
+}
+
+impl<'a, T: Expr> Expr for Volatile<'a, T> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        let mut new_context = context.clone();
+        new_context.insert(self.name, *self.destination);
+        let value = self.inner.exec_expr(&new_context);
+        *self.destination = value.as_int();
+        value
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.inner.referenced_names(names);
+    }
+}
+
+pub trait Expr: IntoDynExpr {
+    fn exec_expr(&mut self, context: &Context) -> Value;
+
+    // Value known without a context, if any.
+    fn literal(&self) -> Option<u64> {
+        None
+    }
+
+    // The same expression with what is known without a context folded away, it evaluates to the
+    // same value. Nodes that can't fold anything come back as they are.
+    fn simplify<'a>(self: Box<Self>) -> Box<dyn Expr + 'a> where Self: 'a {
+        self.into_dyn_expr()
+    }
+
+    // Appends the context keys read by `Constant`s anywhere in this expression.
+    fn referenced_names(&self, _names: &mut Vec<&'static str>) {}
+
+    // Appends ops leaving the value on top of the stack.
+    fn compile_expr(&self, _ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        Err(Unsupported(std::any::type_name::<Self>()))
+    }
+}
+
+pub trait Stmt: AsDynStmt {
+    fn exec_stmt(&mut self, context: &mut Context);
+
+    fn label(&self) -> &'static str {
+        "stmt"
+    }
+
+    // Appends the statements a stepper runs one at a time, a Seq or Block is split up.
+    fn atomic_stmts<'s>(&'s mut self, out: &mut Vec<&'s mut dyn Stmt>) {
+        out.push(self.as_dyn_stmt());
+    }
+
+    // Appends the context keys read by `Constant`s anywhere in this statement.
+    fn referenced_names(&self, _names: &mut Vec<&'static str>) {}
+
+    fn compile_stmt(&self, _ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        Err(Unsupported(std::any::type_name::<Self>()))
+    }
+}
+
+// Evaluation reporting problems instead of panicking, subexpressions fail through `?`.
+pub trait TryExpr: Expr {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RuntimeError {
+    UndefinedName(&'static str),
+}
+
+impl Expr for Box<dyn TryExpr> {
+    fn exec_expr(&mut self, context: &Context) -> Value {
+        self.as_mut().exec_expr(context)
+    }
+
+    fn literal(&self) -> Option<u64> {
+        self.as_ref().literal()
+    }
+
+    fn compile_expr(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.as_ref().compile_expr(ops)
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.as_ref().referenced_names(names);
+    }
+}
+
+impl TryExpr for Box<dyn TryExpr> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        self.as_mut().try_exec_expr(context)
+    }
+}
+
+// Statements run with `try_exec_expr`, the first error stops the program and is returned.
+pub trait TryStmt: Stmt {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError>;
+}
+
+impl Stmt for Box<dyn TryStmt> {
+    fn exec_stmt(&mut self, context: &mut Context) {
+        self.as_mut().exec_stmt(context);
+    }
+
+    fn label(&self) -> &'static str {
+        self.as_ref().label()
+    }
+
+    fn atomic_stmts<'s>(&'s mut self, out: &mut Vec<&'s mut dyn Stmt>) {
+        self.as_mut().atomic_stmts(out);
+    }
+
+    fn compile_stmt(&self, ops: &mut Vec<Op>) -> Result<(), Unsupported> {
+        self.as_ref().compile_stmt(ops)
+    }
+
+    fn referenced_names(&self, names: &mut Vec<&'static str>) {
+        self.as_ref().referenced_names(names);
+    }
+}
+
+impl TryStmt for Box<dyn TryStmt> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        self.as_mut().try_exec_stmt(context)
+    }
+}
+
+// run_loop for fallible loops: an error from `keep_going` or `body` ends the loop and is returned.
+fn try_run_loop(context: &mut Context,
+                mut keep_going: impl FnMut(u64, &Context) -> Result<bool, RuntimeError>,
+                mut body: impl FnMut(&mut Context) -> Result<(), RuntimeError>)
+    -> Result<(), RuntimeError> {
+    let error = RefCell::new(None);
+    run_loop(context, |index, context| {
+        if error.borrow().is_some() {
+            return false;
+        }
+        keep_going(index, context).unwrap_or_else(|e| {
+            error.replace(Some(e));
+            false
+        })
+    }, |context| {
+        if let Err(e) = body(context) {
+            error.replace(Some(e));
+        }
+    });
+    error.into_inner().map_or(Ok(()), Err)
+}
+
+impl<T: TryExpr, W: Write> TryStmt for Print<T, W> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        let value = self.inner.try_exec_expr(context)?;
+        writeln!(self.out, "{}", value).expect("Unable to write output.");
+        Ok(())
+    }
+}
+
+impl TryStmt for Nothing {
+    fn try_exec_stmt(&mut self, _context: &mut Context) -> Result<(), RuntimeError> {
+        Ok(())
+    }
+}
+
+impl<T: TryExpr> TryStmt for Assign<T> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        let value = self.value.try_exec_expr(context)?.as_int();
+        context.insert(self.name, value);
+        Ok(())
+    }
+}
+
+impl<T: TryStmt, U: TryStmt> TryStmt for Seq<T, U> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        self.first.try_exec_stmt(context)?;
+        if !limit_hit() {
+            self.second.try_exec_stmt(context)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: TryStmt> TryStmt for Block<S> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        for stmt in &mut self.stmts {
+            if limit_hit() {
+                break;
+            }
+            stmt.try_exec_stmt(context)?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: u32, T: TryStmt> TryStmt for Repeat<N, T> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        try_run_loop(context, |index, _| Ok(index < N as u64),
+                     |context| self.inner.try_exec_stmt(context))
+    }
+}
+
+impl<T: TryStmt> TryStmt for RepeatN<T> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        let times = self.times as u64;
+        try_run_loop(context, |index, _| Ok(index < times),
+                     |context| self.inner.try_exec_stmt(context))
+    }
+}
+
+impl<C: TryExpr, B: TryStmt> TryStmt for While<C, B> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        try_run_loop(context, |_, context| Ok(self.cond.try_exec_expr(context)?.is_true()),
+                     |context| self.body.try_exec_stmt(context))
+    }
+}
+
+impl<C: TryExpr, B: TryStmt> TryStmt for RepeatUntil<C, B> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        let done = Cell::new(false);
+        try_run_loop(context, |index, _| Ok(index < self.max as u64 && !done.get()), |context| {
+            self.body.try_exec_stmt(context)?;
+            done.set(self.cond.try_exec_expr(context)?.is_true());
+            Ok(())
+        })
+    }
+}
+
+impl<B: TryStmt> TryStmt for WithLimit<B> {
+    fn try_exec_stmt(&mut self, context: &mut Context) -> Result<(), RuntimeError> {
+        with_step_budget(self.max_steps, || self.body.try_exec_stmt(context))
+    }
+}
+
+impl TryExpr for u64 {
+    fn try_exec_expr(&mut self, _context: &Context) -> Result<Value, RuntimeError> {
+        Ok(Value::Int(*self))
+    }
+}
+
+impl TryExpr for Constant {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        context.get(self.name).map(|&n| Value::Int(n)).ok_or(RuntimeError::UndefinedName(self.name))
+    }
+}
+
+impl TryExpr for LoopIndex {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        Ok(self.exec_expr(context))
+    }
+}
+
+impl<C: TryExpr, T: TryExpr, F: TryExpr> TryExpr for When<C, T, F> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        if self.condition.try_exec_expr(context)?.is_true() {
+            self.true_val.try_exec_expr(context)
+        } else {
+            self.false_val.try_exec_expr(context)
+        }
+    }
+}
+
+impl<A: TryExpr, B: TryExpr> TryExpr for Mod<A, B> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let a = self.a.try_exec_expr(context)?.as_int();
+        Ok(Value::Int(a.checked_rem(self.b.try_exec_expr(context)?.as_int()).unwrap_or(0)))
+    }
+}
+
+impl<A: TryExpr, B: TryExpr> TryExpr for IDiv<A, B> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let a = self.a.try_exec_expr(context)?.as_int();
+        Ok(Value::Int(a.checked_div(self.b.try_exec_expr(context)?.as_int()).unwrap_or(0)))
+    }
+}
+
+impl<A: TryExpr, B: TryExpr> TryExpr for CheckedAdd<A, B> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let a = self.a.try_exec_expr(context)?.as_int();
+        Ok(Value::Int(a.saturating_add(self.b.try_exec_expr(context)?.as_int())))
+    }
+}
+
+impl<A: TryExpr, B: TryExpr> TryExpr for CheckedMul<A, B> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let a = self.a.try_exec_expr(context)?.as_int();
+        Ok(Value::Int(a.saturating_mul(self.b.try_exec_expr(context)?.as_int())))
+    }
+}
+
+impl TryExpr for Now {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        Ok(self.exec_expr(context))
+    }
+}
+
+impl TryExpr for ReadFrom<'_> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        Ok(self.exec_expr(context))
+    }
+}
+
+// Nothing is stored when `inner` fails.
+impl<T: TryExpr> TryExpr for SaveIn<'_, T> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let value = self.inner.try_exec_expr(context)?;
+        *self.destination = value.as_int();
+        Ok(value)
+    }
+}
+
+impl<T: TryExpr> TryExpr for Volatile<'_, T> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let mut new_context = context.clone();
+        new_context.insert(self.name, *self.destination);
+        let value = self.inner.try_exec_expr(&new_context)?;
+        *self.destination = value.as_int();
+        Ok(value)
+    }
+}
+
+impl<A: TryExpr, B: TryExpr> TryExpr for Less<A, B> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let a = self.a.try_exec_expr(context)?.as_int();
+        Ok(Value::Bool(a < self.b.try_exec_expr(context)?.as_int()))
+    }
+}
+
+impl<S: TryExpr, O: TryExpr> TryExpr for Choose<S, O> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let selector = self.selector.try_exec_expr(context)?;
+        self.option(selector).map_or(Ok(Value::Int(0)), |option| option.try_exec_expr(context))
+    }
+}
+
+// A failed evaluation isn't cached, the next one tries again.
+impl<T: TryExpr> TryExpr for Memo<T> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        if let Some(&value) = self.cache.get() {
+            return Ok(value);
+        }
+        let value = self.inner.try_exec_expr(context)?;
+        Ok(*self.cache.get_or_init(|| value))
+    }
+}
+
+impl<T: TryExpr> TryExpr for Pipe<T> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let value = self.input.try_exec_expr(context)?.as_int();
+        Ok(self.run_stages(value))
+    }
+}
+
+// Only values that were computed are written down.
+impl<T: TryExpr> TryExpr for Probe<T> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let value = self.inner.try_exec_expr(context)?;
+        self.log.borrow_mut().push((self.label, value));
+        Ok(value)
+    }
+}
+
+impl<T: TryExpr> TryExpr for Fold<T> {
+    fn try_exec_expr(&mut self, context: &Context) -> Result<Value, RuntimeError> {
+        let values = self.arrays.borrow().get(self.slot).cloned().unwrap_or_default();
+        let mut inner = context.clone();
+        let acc = values.into_iter().try_fold(self.init, |acc, value| {
+            inner.insert(ACCUMULATOR, acc);
+            inner.insert(ELEMENT, value);
+            self.combine.try_exec_expr(&inner).map(Value::as_int)
+        })?;
+        Ok(Value::Int(acc))
+    }
+}
+
+// Instructions of a stack machine, jump targets are indices into the op list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    PushConst(u64),
+    LoadVar(&'static str),
+    Print,
+    Mod,
+    IDiv,
+    Add,
+    Mul,
+    // Pops the condition and jumps when it is zero.
+    WhenJump(usize),
+    Jump(usize),
+    PushCounter(u32),
+    // Leaves the loop when the counter on top reaches zero, decrements it otherwise.
+    CountDown(usize),
+}
+
+// Names the node type that has no compiled form.
+#[derive(Debug, PartialEq)]
+pub struct Unsupported(&'static str);
+
+// Step limits and loop indices exist only in the tree walker, nodes using them don't compile.
+fn compile(stmt: &dyn Stmt) -> Result<Vec<Op>, Unsupported> {
+    let mut ops = Vec::new();
+    stmt.compile_stmt(&mut ops)?;
+    Ok(ops)
+}
+
+fn compile_repeat<T: Stmt>(times: u32, inner: &T, ops: &mut Vec<Op>)
+    -> Result<(), Unsupported> {
+    ops.push(Op::PushCounter(times));
+    let start = ops.len();
+    ops.push(Op::CountDown(0));
+    inner.compile_stmt(ops)?;
+    ops.push(Op::Jump(start));
+    ops[start] = Op::CountDown(ops.len());
+    Ok(())
+}
+
+fn run_ops(ops: &[Op], context: &Context) {
+    run_ops_to(ops, context, &mut Console);
+}
+
+fn run_ops_to<W: Write>(ops: &[Op], context: &Context, out: &mut W) {
+    fn pop(stack: &mut Vec<u64>) -> u64 {
+        stack.pop().expect("Operand stack is empty.")
+    }
+
+    let mut stack = Vec::new();
+    let mut counters: Vec<u32> = Vec::new();
+    let mut pc = 0;
+    while let Some(&op) = ops.get(pc) {
+        pc += 1;
+        match op {
+            Op::PushConst(n) => stack.push(n),
+            Op::LoadVar(name) => stack.push(*context.get(name)
+                .unwrap_or_else(|| panic!("{} not found", name))),
+            Op::Print => writeln!(out, "{}", pop(&mut stack)).expect("Unable to write output."),
+            Op::Mod | Op::IDiv | Op::Add | Op::Mul => {
+                let b = pop(&mut stack);
+                let a = pop(&mut stack);
+                stack.push(match op {
+                    Op::Mod => a.checked_rem(b).unwrap_or(0),
+                    Op::IDiv => a.checked_div(b).unwrap_or(0),
+                    Op::Add => a.saturating_add(b),
+                    _ => a.saturating_mul(b),
+                });
+            }
+            Op::WhenJump(target) => if pop(&mut stack) == 0 {
+                pc = target;
+            },
+            Op::Jump(target) => pc = target,
+            Op::PushCounter(n) => counters.push(n),
+            Op::CountDown(target) => match counters.last_mut() {
+                Some(0) => {
+                    counters.pop();
+                    pc = target;
+                }
+                Some(n) => *n -= 1,
+                None => panic!("No loop counter."),
+            },
+        }
+    }
+}
+
+// Simplifies both operands of a binary node built by `build`, literal operands fold the node
+// into its value.
+fn simplify_operands<'a, N: Expr + 'a>(a: Box<dyn Expr + 'a>, b: Box<dyn Expr + 'a>,
+                                       build: fn(Box<dyn Expr + 'a>, Box<dyn Expr + 'a>) -> N)
+    -> Box<dyn Expr + 'a> {
+    let (a, b) = (a.simplify(), b.simplify());
+    let literal = a.literal().is_some() && b.literal().is_some();
+    let mut node = build(a, b);
+    if literal {
+        Box::new(node.exec_expr(&Context::new()).as_int())
+    } else {
+        Box::new(node)
+    }
+}
+
+// Random tree of literals, `keys` lookups, `When` and arithmetic, at most `depth` levels
+// below the root. Small literals make zero conditions and divisors likely.
+fn random_expr(rng: &mut impl Rng, keys: &[&'static str], depth: u32) -> Box<dyn Expr> {
+    let node = if depth == 0 { 0 } else { rng.gen_range(0..6) };
+    if node == 0 {
+        return match keys.get(rng.gen_range(0..=keys.len())) {
+            Some(&key) => Box::new(constant(key)),
+            None => Box::new(rng.gen_range(0..5u64)),
+        };
+    }
+    let mut sub = || random_expr(rng, keys, depth - 1);
+    match node {
+        1 => Box::new(when(sub(), sub(), sub())),
+        2 => Box::new(modulo(sub(), sub())),
+        3 => Box::new(idiv(sub(), sub())),
+        4 => Box::new(checked_add(sub(), sub())),
+        _ => Box::new(checked_mul(sub(), sub())),
+    }
+}
+
+// Any statement seen as a `dyn Stmt`, borrowing nodes included.
+pub trait AsDynStmt {
+    fn as_dyn_stmt(&mut self) -> &mut dyn Stmt;
+}
+
+impl<S: Stmt> AsDynStmt for S {
+    fn as_dyn_stmt(&mut self) -> &mut dyn Stmt {
+        self
+    }
+}
+
+// Only owning nodes can be cloned, nodes borrowing `&mut u64` are neither Clone nor 'static.
+pub trait CloneStmt: Stmt {
+    fn clone_box(&self) -> Box<dyn Stmt>;
+}
+
+impl<S: Stmt + Clone + 'static> CloneStmt for S {
+    fn clone_box(&self) -> Box<dyn Stmt> {
+        Box::new(self.clone())
+    }
+}
+
+fn main() {
+    let mut context = context_from(&[("x", 0), ("y", 10)]).expect("Keys are unique.");
+
+    let mut program = seq(
+        print(when(constant("x"), 1u64, 2u64)),
+        print(when(constant("y"), 1u64, 2u64))
+    );
+    program.exec_stmt(&mut context);
+
+    let seq1 = seq(print(1u64), nothing());
+    let mut s1 = seq1.shorten_1();
+    s1.exec_stmt(&mut context);
+
+    let seq2 = seq(nothing(), print(2u64));
+    let mut s2 = seq2.shorten_2();
+    s2.exec_stmt(&mut context);
+
+    let seq3 = seq(nothing(), nothing());
+    let mut s3 = seq3.collapse();
+    s3.exec_stmt(&mut context);
+
+    let mut do_nothing = nothing();
+    do_nothing.exec_stmt(&mut context);
+
+    let mut repeat_prog = repeat::<10, _>(print(constant("x")));
+    repeat_prog.exec_stmt(&mut context);
+
+    let mut folded = print(when(1u64, constant("y"), 0u64).fold());
+    folded.exec_stmt(&mut context);
+
+    let mut chosen = print(choose(constant("x"), vec![Box::new(10u64), Box::new(20u64)]));
+    chosen.exec_stmt(&mut context);
+
+    let mut parity = print(when(modulo(constant("y"), 2u64), 1u64, 0u64));
+    parity.exec_stmt(&mut context);
+    let mut halves = print(idiv(constant("y"), 2u64));
+    halves.exec_stmt(&mut context);
+
+    let mut sum_product = print(checked_add(constant("y"), checked_mul(constant("y"), 3u64)));
+    sum_product.exec_stmt(&mut context);
+
+    let mut bounded = with_limit(3, while_loop(1u64, print(constant("y"))));
+    bounded.exec_stmt(&mut context);
+
+    let mut shadowed = scope(vec![("y", 1), ("z", 2)], print(idiv(constant("y"), constant("z"))));
+    shadowed.exec_stmt(&mut context);
+
+    let mut counting = repeat::<3, _>(seq(run_once(print(100u64)), print(loop_index())));
+    counting.exec_stmt(&mut context);
+
+    let probes = Rc::new(RefCell::new(Vec::new()));
+    let mut probed = print(checked_add(probe("y", probes.clone(), constant("y")), 1u64));
+    probed.exec_stmt(&mut context);
+    println!("Probed values: {:?}", probes.borrow());
+
+    let mut compared = seq(print(less(constant("y"), 100u64)),
+                           print(when(less(constant("y"), 100u64), 1u64, 2u64)));
+    compared.exec_stmt(&mut context);
+
+    let arrays: VecContext = Rc::new(RefCell::new(HashMap::from([("xs", vec![1, 2, 3])])));
+    let mut doubled = map_in_place(arrays.clone(), "xs", checked_mul(element(), 2u64));
+    doubled.exec_stmt(&mut context);
+    println!("Doubled array: {:?}", arrays.borrow()["xs"]);
+    let mut total = print(fold(arrays.clone(), "xs", 0, checked_add(accumulator(), element())));
+    total.exec_stmt(&mut context);
+
+    let mut cached = repeat::<2, _>(print(memo(checked_mul(constant("y"), constant("y")))));
+    cached.exec_stmt(&mut context);
+
+    let mut fuzzed = print(random_expr(&mut rand::thread_rng(), &["x", "y"], 3));
+    fuzzed.exec_stmt(&mut context);
+
+    let mut piped = print(pipe(constant("y"), vec![Box::new(|v| v + 1), Box::new(|v| v * v)]));
+    piped.exec_stmt(&mut context);
+
+    match checked_add(constant("y"), constant("w")).try_exec_expr(&context) {
+        Ok(v) => println!("y + w = {}", v),
+        Err(e) => eprintln!("Runtime error: {:?}", e),
+    }
+    let mut fallible = try_choose(constant("y"), vec![Box::new(0u64), Box::new(constant("w"))]);
+    match fallible.try_exec_expr(&context) {
+        Ok(v) => println!("Chosen = {}", v),
+        Err(e) => eprintln!("Runtime error: {:?}", e),
+    }
+    let mut checked = try_block(vec![Box::new(print(constant("y"))),
+                                     Box::new(print(constant("w")))]);
+    if let Err(e) = checked.try_exec_stmt(&mut context) {
+        eprintln!("Runtime error: {:?}", e);
+    }
+
+    let mut increment = assign("y", checked_add(constant("y"), 1u64));
+    println!("Changes: {:?}", run_and_diff(&mut increment, &mut context));
+    println!("Keys the increment never reads: {:?}", unused_keys(&increment, &context));
+
+    let times = Rc::new(RefCell::new(Vec::new()));
+    let mut benchmark = timed("1000 steps", times.clone(), repeat::<1000, _>(nothing()));
+    benchmark.exec_stmt(&mut context);
+    println!("Timings: {:?}", times.borrow());
+
+    let dump = Rc::new(RefCell::new(Vec::new()));
+    dump_context(dump.clone()).exec_stmt(&mut context);
+    println!("Context: {:?}", dump.borrow());
+
+    let mut count_up = assign("y", checked_add(constant("y"), 1u64));
+    let limited = run_while_limited(&mut modulo(constant("y"), 10u64), &mut count_up,
+                                    &mut context, 20);
+    println!("Limited while: {:?}, y = {}", limited, context["y"]);
 
-    let seq3 = seq(nothing(), nothing());
-    let mut s3 = seq3.collapse();
-    s3.exec_stmt(&context);
+    let mut halvings = seq(assign("h", constant("y")),
+                           repeat_until(100, when(constant("h"), 0u64, 1u64),
+                                        assign("h", idiv(constant("h"), 2u64))));
+    halvings.exec_stmt(&mut context);
 
-    let mut do_nothing = nothing();
-    do_nothing.exec_stmt(&context);
+    let mut clock = seq(print(now()), print(now_at(UNIX_EPOCH + Duration::from_secs(86_400))));
+    clock.exec_stmt(&mut context);
 
-    let mut repeat_prog = repeat::<10, _>(print(constant("x")));
-    repeat_prog.exec_stmt(&context);
+    let mut check = assert(constant("y"), "y must not be zero");
+    check.exec_stmt(&mut context);
 
     let mut a = 10u64;
     let b = 20u64;
@@ -215,6 +2172,39 @@ fn main() {
                                                   11u64, 22u64));
     println!("Result of Volatile: {}", vol_prog.exec_expr(&context));
     println!("v after Volatile = {}", v);
+
+    let trace = ExecutionTrace::new();
+    let mut traced = seq(print_to(constant("y"), trace.clone()),
+                         mark("printed y", trace.clone()));
+    traced.exec_stmt(&mut context);
+    println!("Trace: {:?}", trace.entries());
+
+    let mut program = block(vec![
+        Box::new(print(constant("y"))),
+        Box::new(seq(nothing(), print(2u64))),
+        Box::new(repeat::<2, _>(print(1u64))),
+    ]);
+    for step in program.steps(context.clone()) {
+        println!("Step {}: {} with context {:?}", step.index, step.label, step.context);
+    }
+
+    match parse_program("repeat 2 { print when(constant(x), 1, constant(y)); }; nothing;") {
+        Ok(mut parsed) => parsed.exec_stmt(&mut context),
+        Err(e) => eprintln!("Parse error: {:?}", e),
+    }
+
+    let compiled = seq(print(when(constant("x"), 1u64, 2u64)),
+                       repeat::<2, _>(print(checked_mul(constant("y"), 2u64))));
+    match compile(&compiled) {
+        Ok(ops) => run_ops(&ops, &context),
+        Err(e) => eprintln!("Can't compile: {:?}", e),
+    }
+
+    let template = seq(print(constant("x")), print(constant("y")));
+    let mut first_run = template.clone_box();
+    let mut second_run = template.clone_box();
+    first_run.exec_stmt(&mut context);
+    second_run.exec_stmt(&mut HashMap::from([("x", 1), ("y", 2)]));
 }
 
 #[cfg(test)]
@@ -231,46 +2221,47 @@ mod tests {
         log: Rc<RefCell<Vec<&'static str>>>,
     }
     impl Stmt for Recorder {
-        fn exec_stmt(&mut self, _context: &Context) {
+        fn exec_stmt(&mut self, _context: &mut Context) {
             self.log.borrow_mut().push(self.label);
         }
     }
 
     // Ta struktura zlicza, ile razy ona i jej klony były wywołane
+    #[derive(Clone)]
     struct CounterExpr {
         calls: Rc<RefCell<u32>>,
         value: u64,
     }
     impl Expr for CounterExpr {
-        fn exec_expr(&mut self, _context: &Context) -> u64 {
+        fn exec_expr(&mut self, _context: &Context) -> Value {
             *self.calls.borrow_mut() += 1;
-            self.value
+            Value::Int(self.value)
         }
     }
 
     #[test]
     fn print_struct_executes_inner_once() {
-        let ctx = HashMap::from([("x", 0), ("y", 0)]);
+        let mut ctx = HashMap::from([("x", 0), ("y", 0)]);
         let calls = Rc::new(RefCell::new(0u32));
         let ce = CounterExpr {
             calls: calls.clone(),
             value: 123,
         };
         let mut p = print(ce);
-        p.exec_stmt(&ctx);
+        p.exec_stmt(&mut ctx);
         assert_eq!(*calls.borrow(), 1);
     }
 
     #[test]
     fn nothing_struct_does_nothing() {
-        let ctx = HashMap::from([("x", 0), ("y", 0)]);
+        let mut ctx = HashMap::from([("x", 0), ("y", 0)]);
         let mut n = Nothing;
-        n.exec_stmt(&ctx);
+        n.exec_stmt(&mut ctx);
     }
 
     #[test]
     fn seq_struct_executes_in_order() {
-        let ctx = HashMap::from([("x", 0), ("y", 0)]);
+        let mut ctx = HashMap::from([("x", 0), ("y", 0)]);
         let log = Rc::new(RefCell::new(Vec::new()));
         let r1 = Recorder {
             label: "first",
@@ -281,13 +2272,13 @@ mod tests {
             log: log.clone(),
         };
         let mut s = seq(r1, r2);
-        s.exec_stmt(&ctx);
+        s.exec_stmt(&mut ctx);
         assert_eq!(&*log.borrow(), &["first", "second"]);
     }
 
     #[test]
     fn seq_shorten_1_discards_trailing_nothing_and_returns_first() {
-        let ctx = HashMap::from([("x", 0), ("y", 0)]);
+        let mut ctx = HashMap::from([("x", 0), ("y", 0)]);
         let log = Rc::new(RefCell::new(Vec::new()));
         let r = Recorder {
             label: "A",
@@ -296,13 +2287,13 @@ mod tests {
         let s = seq(r, nothing());
         // shorten_1 should return the first statement (Recorder)
         let mut first_only = s.shorten_1();
-        first_only.exec_stmt(&ctx);
+        first_only.exec_stmt(&mut ctx);
         assert_eq!(&*log.borrow(), &["A"]);
     }
 
     #[test]
     fn seq_shorten_2_discards_leading_nothing_and_returns_second() {
-        let ctx = HashMap::from([("x", 0), ("y", 0)]);
+        let mut ctx = HashMap::from([("x", 0), ("y", 0)]);
         let log = Rc::new(RefCell::new(Vec::new()));
         let r = Recorder {
             label: "B",
@@ -311,7 +2302,7 @@ mod tests {
         let s = seq(nothing(), r);
         // shorten_2 should return the second statement (Recorder)
         let mut second_only = s.shorten_2();
-        second_only.exec_stmt(&ctx);
+        second_only.exec_stmt(&mut ctx);
         assert_eq!(&*log.borrow(), &["B"]);
     }
 
@@ -330,8 +2321,124 @@ mod tests {
     }
 
     #[test]
-    fn repeat_struct_runs_n_times() {
+    fn when_fold_literal_condition() {
+        let ctx = HashMap::new();
+        let true_calls = Rc::new(RefCell::new(0u32));
+        let false_calls = Rc::new(RefCell::new(0u32));
+        let a = CounterExpr {calls: true_calls.clone(), value: 7};
+        let b = CounterExpr {calls: false_calls.clone(), value: 8};
+
+        let mut folded = when(0u64, a.clone(), b.clone()).fold();
+        assert_eq!(folded.exec_expr(&ctx), 8);
+        assert_eq!((*true_calls.borrow(), *false_calls.borrow()), (0, 1));
+
+        let mut folded = when(5u64, a, b).fold();
+        assert_eq!(folded.exec_expr(&ctx), 7);
+        assert_eq!((*true_calls.borrow(), *false_calls.borrow()), (1, 1));
+    }
+
+    #[test]
+    fn when_fold_keeps_runtime_condition() {
+        let mut folded = when(constant("x"), 7u64, 8u64).fold();
+        assert_eq!(folded.literal(), None);
+        assert_eq!(folded.exec_expr(&HashMap::from([("x", 0)])), 8);
+        assert_eq!(folded.exec_expr(&HashMap::from([("x", 1)])), 7);
+        assert_eq!(when(0u64, 7u64, when(1u64, 8u64, 9u64).fold()).fold().literal(), Some(8));
+    }
+
+    fn counted(value: u64, calls: &[Rc<RefCell<u32>>]) -> Vec<Box<dyn Expr>> {
+        calls.iter().enumerate()
+            .map(|(i, c)| Box::new(CounterExpr {calls: c.clone(), value: value + i as u64})
+                as Box<dyn Expr>)
+            .collect()
+    }
+
+    #[test]
+    fn choose_evaluates_only_selected_option() {
+        let ctx = HashMap::from([("i", 2)]);
+        let calls: Vec<_> = (0..3).map(|_| Rc::new(RefCell::new(0u32))).collect();
+        let mut first = choose(0u64, counted(10, &calls));
+        assert_eq!(first.exec_expr(&ctx), 10);
+        let mut third = choose(constant("i"), counted(10, &calls));
+        assert_eq!(third.exec_expr(&ctx), 12);
+        let counts: Vec<u32> = calls.iter().map(|c| *c.borrow()).collect();
+        assert_eq!(counts, vec![1, 0, 1]);
+    }
+
+    #[test]
+    fn choose_clamps_out_of_range() {
         let ctx = HashMap::new();
+        let calls: Vec<_> = (0..3).map(|_| Rc::new(RefCell::new(0u32))).collect();
+        assert_eq!(choose(3u64, counted(10, &calls)).exec_expr(&ctx), 12);
+        assert_eq!(choose(u64::MAX, counted(10, &calls)).exec_expr(&ctx), 12);
+        assert_eq!(*calls[2].borrow(), 2);
+        assert_eq!(*calls[0].borrow() + *calls[1].borrow(), 0);
+        assert_eq!(choose(1u64, Vec::new()).exec_expr(&ctx), 0);
+    }
+
+    #[test]
+    fn mod_and_idiv_structs() {
+        let ctx = HashMap::from([("x", 7)]);
+        assert_eq!(modulo(7u64, 3u64).exec_expr(&ctx), 1);
+        assert_eq!(idiv(7u64, 3u64).exec_expr(&ctx), 2);
+        assert_eq!(modulo(constant("x"), 2u64).exec_expr(&ctx), 1);
+        assert_eq!(idiv(constant("x"), 7u64).exec_expr(&ctx), 1);
+    }
+
+    #[test]
+    fn mod_and_idiv_by_zero_give_zero() {
+        let ctx = HashMap::new();
+        assert_eq!(modulo(7u64, 0u64).exec_expr(&ctx), 0);
+        assert_eq!(idiv(7u64, 0u64).exec_expr(&ctx), 0);
+    }
+
+    #[test]
+    fn checked_add_and_mul_in_range() {
+        let ctx = HashMap::from([("x", 6), ("y", 7)]);
+        assert_eq!(checked_add(constant("x"), constant("y")).exec_expr(&ctx), 13);
+        assert_eq!(checked_mul(constant("x"), constant("y")).exec_expr(&ctx), 42);
+        assert_eq!(checked_add(u64::MAX - 1, 1u64).exec_expr(&ctx), u64::MAX);
+        assert_eq!(checked_mul(u64::MAX, 1u64).exec_expr(&ctx), u64::MAX);
+    }
+
+    #[test]
+    fn checked_add_and_mul_saturate_on_overflow() {
+        let big = 1u64 << 32;
+        let ctx = HashMap::from([("a", big), ("b", big), ("max", u64::MAX)]);
+        assert_eq!(checked_mul(constant("a"), constant("b")).exec_expr(&ctx), u64::MAX);
+        assert_eq!(checked_mul(constant("a"), big - 1).exec_expr(&ctx), u64::MAX - big + 1);
+        assert_eq!(checked_add(constant("max"), 1u64).exec_expr(&ctx), u64::MAX);
+        assert_eq!(checked_add(constant("max"), constant("max")).exec_expr(&ctx), u64::MAX);
+    }
+
+    #[test]
+    fn less_yields_bool_driving_when() {
+        let ctx = HashMap::from([("x", 3), ("y", 7)]);
+        assert_eq!(less(constant("x"), constant("y")).exec_expr(&ctx), Value::Bool(true));
+        assert_eq!(less(constant("y"), constant("x")).exec_expr(&ctx), Value::Bool(false));
+        assert_eq!(when(less(constant("x"), constant("y")), 1u64, 2u64).exec_expr(&ctx), 1);
+        assert_eq!(when(less(constant("y"), 7u64), 1u64, 2u64).exec_expr(&ctx), 2);
+        // a Bool used as a number is 0 or 1
+        assert_eq!(checked_add(less(1u64, 2u64), 10u64).exec_expr(&ctx), 11);
+        let trace = ExecutionTrace::new();
+        print_to(less(1u64, 2u64), trace.clone()).exec_stmt(&mut ctx.clone());
+        assert_eq!(trace.entries(), vec!["true"]);
+    }
+
+    #[test]
+    fn probe_records_when_condition() {
+        let ctx = HashMap::from([("x", 0), ("y", 3)]);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut picked = when(probe("cond", log.clone(), constant("y")), 1u64, 2u64);
+        assert_eq!(picked.exec_expr(&ctx), 1);
+        let mut other = when(probe("cond", log.clone(), constant("x")), 1u64, 2u64);
+        assert_eq!(other.exec_expr(&ctx), 2);
+        assert_eq!(*log.borrow(), vec![("cond", Value::Int(3)), ("cond", Value::Int(0))]);
+    }
+
+    #[test]
+    fn repeat_struct_runs_n_times() {
+        let mut ctx = HashMap::new();
         let log = Rc::new(RefCell::new(Vec::new()));
         let r = Recorder {
             label: "tick",
@@ -339,10 +2446,368 @@ mod tests {
         };
 
         let mut rep = repeat::<3, _>(r);
-        rep.exec_stmt(&ctx);
+        rep.exec_stmt(&mut ctx);
         assert_eq!(&*log.borrow(), &["tick", "tick", "tick"]);
     }
 
+    #[test]
+    fn pipe_applies_stages_in_order() {
+        let ctx = HashMap::from([("x", 5)]);
+        let mut p = pipe(constant("x"), vec![Box::new(|v| v + 1), Box::new(|v| v * 2)]);
+        assert_eq!(p.exec_expr(&ctx), 12);
+    }
+
+    #[test]
+    fn pipe_without_stages_returns_input() {
+        let ctx = HashMap::new();
+        assert_eq!(pipe(5u64, Vec::new()).exec_expr(&ctx), 5);
+    }
+
+    #[test]
+    fn now_with_injected_time() {
+        let ctx = HashMap::new();
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_999);
+        assert_eq!(now_at(at).exec_expr(&ctx), 1_700_000_000);
+        assert_eq!(now_at(UNIX_EPOCH - Duration::from_secs(5)).exec_expr(&ctx), 0);
+        assert!(now().exec_expr(&ctx).as_int() >= 1_700_000_000);
+    }
+
+    #[test]
+    fn memo_evaluates_inner_once() {
+        let mut ctx = HashMap::new();
+        let calls = Rc::new(RefCell::new(0u32));
+        let mut m = memo(CounterExpr {calls: calls.clone(), value: 5});
+        assert_eq!(m.exec_expr(&ctx), 5);
+        assert_eq!(m.exec_expr(&ctx), 5);
+        let mut program = repeat::<3, _>(print_to(m, ExecutionTrace::new()));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(*calls.borrow(), 1);
+    }
+
+    #[test]
+    fn memo_ignores_later_context_changes() {
+        let mut m = memo(constant("x"));
+        assert_eq!(m.exec_expr(&HashMap::from([("x", 1)])), 1);
+        assert_eq!(m.exec_expr(&HashMap::from([("x", 2)])), 1);
+    }
+
+    #[test]
+    fn repeat_until_stops_at_target() {
+        let mut ctx = HashMap::from([("n", 0)]);
+        let mut program = repeat_until(10, idiv(constant("n"), 3u64),
+                                       assign("n", checked_add(constant("n"), 1u64)));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(ctx, HashMap::from([("n", 3)]));
+    }
+
+    #[test]
+    fn repeat_until_runs_at_most_max() {
+        let mut ctx = HashMap::from([("n", 0)]);
+        let mut program = repeat_until(4, 0u64, assign("n", checked_add(constant("n"), 1u64)));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(ctx["n"], 4);
+        // the body runs before the first check
+        repeat_until(4, 1u64, assign("n", 0u64)).exec_stmt(&mut ctx);
+        assert_eq!(ctx["n"], 0);
+        let mut limited = with_limit(2, repeat_until(4, 0u64, assign("n", loop_index())));
+        limited.exec_stmt(&mut ctx);
+        assert_eq!(ctx["n"], 1);
+    }
+
+    #[test]
+    fn while_struct_stops_on_zero_condition() {
+        let mut ctx = HashMap::from([("x", 0)]);
+        let calls = Rc::new(RefCell::new(0u32));
+        let ce = CounterExpr {calls: calls.clone(), value: 1};
+        let mut w = while_loop(constant("x"), print(ce));
+        w.exec_stmt(&mut ctx);
+        assert_eq!(*calls.borrow(), 0);
+    }
+
+    #[test]
+    fn with_limit_stops_infinite_while() {
+        let mut ctx = HashMap::from([("x", 1)]);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let r = Recorder {label: "tick", log: log.clone()};
+        let mut program = with_limit(4, while_loop(constant("x"), r));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(log.borrow().len(), 4);
+        assert_eq!(ctx, HashMap::from([("x", 1)]));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(log.borrow().len(), 8);
+    }
+
+    #[test]
+    fn run_while_limited_counts_iterations() {
+        let mut ctx = HashMap::from([("n", 2)]);
+        let mut body = assign("n", checked_add(constant("n"), 1u64));
+        let result = run_while_limited(&mut modulo(constant("n"), 5u64), &mut body, &mut ctx, 3);
+        assert_eq!(result, Ok(3));
+        assert_eq!(ctx, HashMap::from([("n", 5)]));
+        let result = run_while_limited(&mut 0u64, &mut body, &mut ctx, 0);
+        assert_eq!(result, Ok(0));
+    }
+
+    #[test]
+    fn run_while_limited_trips_limit() {
+        let mut ctx = HashMap::new();
+        let trace = ExecutionTrace::new();
+        let result = run_while_limited(&mut 1u64, &mut mark("m", trace.clone()), &mut ctx, 4);
+        assert_eq!(result, Err(LoopLimitExceeded));
+        assert_eq!(trace.entries().len(), 4);
+        assert!(ctx.is_empty());
+
+        // an enclosing WithLimit running out counts as hitting the limit too
+        let result = with_step_budget(2, || {
+            run_while_limited(&mut 1u64, &mut mark("m", trace.clone()), &mut ctx, 10)
+        });
+        assert_eq!(result, Err(LoopLimitExceeded));
+        assert_eq!(trace.entries().len(), 6);
+        assert!(ctx.is_empty());
+    }
+
+    #[test]
+    fn with_limit_nested_charges_outer_budget() {
+        let mut ctx = HashMap::new();
+        let trace = ExecutionTrace::new();
+        let mut program = with_limit(5, seq(
+            with_limit(3, while_loop(1u64, mark("inner", trace.clone()))),
+            while_loop(1u64, mark("outer", trace.clone())),
+        ));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["inner", "inner", "inner", "outer", "outer"]);
+
+        let trace = ExecutionTrace::new();
+        let mut program = with_limit(2, with_limit(10, repeat::<5, _>(mark("r", trace.clone()))));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["r", "r"]);
+        assert!(ctx.is_empty());
+    }
+
+    #[test]
+    fn with_limit_skips_rest_of_body() {
+        let mut ctx = HashMap::new();
+        let trace = ExecutionTrace::new();
+        let mut program = seq(
+            with_limit(3, seq(while_loop(1u64, mark("loop", trace.clone())),
+                              mark("after loop", trace.clone()))),
+            mark("after limit", trace.clone()),
+        );
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["loop", "loop", "loop", "after limit"]);
+
+        // a loop that ends on its own within the budget doesn't abort anything
+        let trace = ExecutionTrace::new();
+        let mut program = with_limit(2, block(vec![
+            Box::new(repeat::<2, _>(mark("r", trace.clone()))),
+            Box::new(mark("done", trace.clone())),
+        ]));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["r", "r", "done"]);
+    }
+
+    #[test]
+    fn with_limit_restores_budget_after_panic() {
+        let mut ctx = HashMap::new();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            with_limit(1, assert(0u64, "boom")).exec_stmt(&mut ctx);
+        }));
+        assert!(result.is_err());
+        assert_eq!(STEPS_LEFT.get(), None);
+        assert!(!limit_hit());
+
+        let trace = ExecutionTrace::new();
+        repeat::<3, _>(mark("m", trace.clone())).exec_stmt(&mut ctx);
+        assert_eq!(trace.entries().len(), 3);
+    }
+
+    #[test]
+    fn with_limit_budget_is_not_in_context() {
+        let mut ctx = HashMap::new();
+        let trace = ExecutionTrace::new();
+        let mut program = with_limit(3, while_loop(1u64, seq(
+            assign("__steps_left", 100u64),
+            mark("m", trace.clone()),
+        )));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries().len(), 3);
+        assert_eq!(ctx, HashMap::from([("__steps_left", 100)]));
+    }
+
+    #[test]
+    fn scope_override_is_visible_only_inside() {
+        let mut ctx = HashMap::from([("y", 3)]);
+        let trace = ExecutionTrace::new();
+        let mut program = seq(
+            scope(vec![("x", 5), ("y", 6)], seq(print_to(constant("x"), trace.clone()),
+                                                print_to(constant("y"), trace.clone()))),
+            print_to(constant("y"), trace.clone()),
+        );
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["5", "6", "3"]);
+        assert_eq!(ctx, HashMap::from([("y", 3)]));
+    }
+
+    #[test]
+    fn scope_repeated_key_restores_original() {
+        let mut ctx = HashMap::from([("x", 1)]);
+        let trace = ExecutionTrace::new();
+        let mut program = scope(vec![("x", 2), ("x", 3)], print_to(constant("x"), trace.clone()));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["3"]);
+        assert_eq!(ctx, HashMap::from([("x", 1)]));
+    }
+
+    #[test]
+    fn map_in_place_doubles_slot() {
+        let mut ctx = HashMap::from([("k", 2)]);
+        let arrays: VecContext = Rc::new(RefCell::new(HashMap::from([("xs", vec![1, 2, 3])])));
+        let mut program = map_in_place(arrays.clone(), "xs", checked_mul(element(), constant("k")));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(arrays.borrow()["xs"], vec![2, 4, 6]);
+        assert_eq!(ctx, HashMap::from([("k", 2)]));
+    }
+
+    #[test]
+    fn map_in_place_missing_slot_is_noop() {
+        let mut ctx = HashMap::new();
+        let arrays: VecContext = Rc::default();
+        map_in_place(arrays.clone(), "xs", 7u64).exec_stmt(&mut ctx);
+        assert!(arrays.borrow().is_empty());
+        assert!(ctx.is_empty());
+    }
+
+    #[test]
+    fn fold_sums_and_multiplies_slot() {
+        let ctx = HashMap::new();
+        let arrays: VecContext = Rc::new(RefCell::new(HashMap::from([("xs", vec![1, 2, 3, 4])])));
+        let mut sum = fold(arrays.clone(), "xs", 0, checked_add(accumulator(), element()));
+        assert_eq!(sum.exec_expr(&ctx), 10);
+        let mut product = fold(arrays.clone(), "xs", 1, checked_mul(accumulator(), element()));
+        assert_eq!(product.exec_expr(&ctx), 24);
+        assert_eq!(arrays.borrow()["xs"], vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn fold_missing_slot_gives_init() {
+        let ctx = HashMap::from([("x", 5)]);
+        let arrays: VecContext = Rc::default();
+        let mut program = fold(arrays, "xs", 7, checked_add(accumulator(), constant("x")));
+        assert_eq!(program.exec_expr(&ctx), 7);
+    }
+
+    #[test]
+    fn unused_keys_reports_unread_names() {
+        let ctx = HashMap::from([("x", 1), ("y", 2)]);
+        let program = print_to(checked_add(constant("x"), 1u64), ExecutionTrace::new());
+        assert_eq!(unused_keys(&program, &ctx), vec!["y"]);
+        let program = seq(assign("y", 5u64), print(constant("x")));
+        assert_eq!(unused_keys(&program, &ctx), vec!["y"]);
+        assert_eq!(unused_keys(&nothing(), &ctx), vec!["x", "y"]);
+    }
+
+    #[test]
+    fn unused_keys_looks_into_every_branch() {
+        let ctx = HashMap::from([("a", 0), ("b", 0), ("c", 0), ("d", 0)]);
+        let program = block(vec![
+            Box::new(while_loop(constant("a"), print(when(0u64, constant("b"), 1u64)))),
+            Box::new(repeat::<0, _>(assert(constant("c"), "never runs"))),
+        ]);
+        assert_eq!(unused_keys(&program, &ctx), vec!["d"]);
+    }
+
+    #[test]
+    fn run_and_diff_reports_assigned_key() {
+        let mut ctx = HashMap::from([("x", 1), ("y", 2)]);
+        let mut program = assign("x", checked_add(constant("x"), constant("y")));
+        assert_eq!(run_and_diff(&mut program, &mut ctx), vec![("x", Some(1), 3)]);
+        assert_eq!(ctx, HashMap::from([("x", 3), ("y", 2)]));
+    }
+
+    #[test]
+    fn run_and_diff_added_and_unchanged_keys() {
+        let mut ctx = HashMap::from([("x", 1)]);
+        let mut program = seq(assign("x", 1u64), assign("new", 5u64));
+        assert_eq!(run_and_diff(&mut program, &mut ctx), vec![("new", None, 5)]);
+        assert!(run_and_diff(&mut nothing(), &mut ctx).is_empty());
+    }
+
+    #[test]
+    fn timed_records_duration_under_label() {
+        let mut ctx = HashMap::from([("x", 1)]);
+        let times = Rc::new(RefCell::new(Vec::new()));
+        let trace = ExecutionTrace::new();
+        let mut program = timed("loop", times.clone(),
+                                repeat::<100, _>(print_to(constant("x"), trace.clone())));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries().len(), 100);
+        let times = times.borrow();
+        assert_eq!(times.len(), 1);
+        assert_eq!(times[0].0, "loop");
+        assert!(times[0].1 > Duration::ZERO);
+    }
+
+    #[test]
+    fn dump_context_captures_sorted_pairs() {
+        let mut ctx = HashMap::from([("y", 2), ("x", 1), ("z", 3)]);
+        let dump = Rc::new(RefCell::new(Vec::new()));
+        let mut program = seq(dump_context(dump.clone()),
+                              seq(assign("a", 7u64), dump_context(dump.clone())));
+        program.exec_stmt(&mut ctx);
+        let expected: Vec<(String, u64)> = [("x", 1), ("y", 2), ("z", 3),
+                                            ("a", 7), ("x", 1), ("y", 2), ("z", 3)]
+            .iter().map(|&(k, v)| (k.to_string(), v)).collect();
+        assert_eq!(*dump.borrow(), expected);
+    }
+
+    #[test]
+    fn dump_context_skips_reserved_keys() {
+        let mut ctx = HashMap::from([("x", 1)]);
+        let dump = Rc::new(RefCell::new(Vec::new()));
+        let mut program = repeat::<2, _>(dump_context(dump.clone()));
+        program.exec_stmt(&mut ctx);
+        let expected = vec![("x".to_string(), 1), ("x".to_string(), 1)];
+        assert_eq!(*dump.borrow(), expected);
+    }
+
+    #[test]
+    fn run_once_inside_repeat_fires_once() {
+        let mut ctx = HashMap::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let r = Recorder {label: "init", log: log.clone()};
+        let mut program = repeat::<3, _>(run_once(r));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(&*log.borrow(), &["init"]);
+        program.exec_stmt(&mut ctx);
+        assert_eq!(&*log.borrow(), &["init"]);
+    }
+
+    #[test]
+    fn assert_struct_passes_on_nonzero() {
+        let mut ctx = HashMap::from([("x", 3)]);
+        let mut a = assert(constant("x"), "x is zero");
+        a.exec_stmt(&mut ctx);
+    }
+
+    #[test]
+    #[should_panic(expected = "x is zero")]
+    fn assert_struct_panics_on_zero() {
+        let mut ctx = HashMap::from([("x", 0)]);
+        let mut a = assert(constant("x"), "x is zero");
+        a.exec_stmt(&mut ctx);
+    }
+
+    #[test]
+    fn context_from_unique_keys() {
+        assert_eq!(context_from(&[("x", 1), ("y", 2)]), Ok(HashMap::from([("x", 1), ("y", 2)])));
+        assert_eq!(context_from(&[]), Ok(HashMap::new()));
+    }
+
+    #[test]
+    fn context_from_rejects_duplicate_key() {
+        assert_eq!(context_from(&[("x", 1), ("y", 2), ("x", 1)]), Err(DuplicateKey("x")));
+    }
+
     #[test]
     fn constant_struct_reads_value() {
         let ctx = HashMap::from([("k", 123u64)]);
@@ -402,7 +2867,7 @@ mod tests {
 
     #[test]
     fn nesting_seq_repeat_order_structs() {
-        let ctx = HashMap::from([("x", 0), ("y", 0)]);
+        let mut ctx = HashMap::from([("x", 0), ("y", 0)]);
         let log = Rc::new(RefCell::new(Vec::new()));
         let r_a = Recorder {
             label: "A",
@@ -413,7 +2878,7 @@ mod tests {
             log: log.clone(),
         };
         let mut program = seq(repeat::<2, _>(r_a), repeat::<3, _>(r_b));
-        program.exec_stmt(&ctx);
+        program.exec_stmt(&mut ctx);
         assert_eq!(&*log.borrow(), &["A", "A", "B", "B", "B"]);
     }
 
@@ -438,10 +2903,423 @@ mod tests {
         assert_eq!(a, 1);
     }
 
+    #[test]
+    fn clone_box_runs_copies_independently() {
+        let calls = Rc::new(RefCell::new(0u32));
+        let ce = CounterExpr {
+            calls: calls.clone(),
+            value: 5,
+        };
+        let program = seq(print(ce.clone()), print(when(constant("x"), ce, 0u64)));
+        let mut first = program.clone_box();
+        let mut second = program.clone_box();
+        first.exec_stmt(&mut HashMap::from([("x", 0)]));
+        assert_eq!(*calls.borrow(), 1);
+        second.exec_stmt(&mut HashMap::from([("x", 1)]));
+        assert_eq!(*calls.borrow(), 3);
+    }
+
+    #[test]
+    fn trace_interleaves_prints_and_marks() {
+        let mut ctx = HashMap::from([("x", 7)]);
+        let trace = ExecutionTrace::new();
+        let mut program = seq(
+            print_to(1u64, trace.clone()),
+            seq(
+                mark("between", trace.clone()),
+                repeat::<2, _>(print_to(constant("x"), trace.clone())),
+            ),
+        );
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["1", "between", "7", "7"]);
+    }
+
+    #[test]
+    fn trace_joins_partial_writes_into_lines() {
+        let mut trace = ExecutionTrace::new();
+        write!(trace, "ab").unwrap();
+        assert!(trace.entries().is_empty());
+        write!(trace, "c\nd\n").unwrap();
+        assert_eq!(trace.entries(), vec!["abc", "d"]);
+    }
+
+    #[test]
+    fn block_struct_runs_all_in_order() {
+        let mut ctx = HashMap::new();
+        let trace = ExecutionTrace::new();
+        let mut b = block(vec![
+            Box::new(mark("A", trace.clone())),
+            Box::new(print_to(2u64, trace.clone())),
+        ]);
+        b.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["A", "2"]);
+    }
+
+    #[test]
+    fn stepper_yields_one_step_per_statement() {
+        let ctx = HashMap::from([("x", 4)]);
+        let trace = ExecutionTrace::new();
+        let mut program = block(vec![
+            Box::new(print_to(constant("x"), trace.clone())),
+            Box::new(seq(mark("middle", trace.clone()), assign("x", 5u64))),
+            Box::new(nothing()),
+        ]);
+        let mut steps = program.steps(ctx.clone());
+
+        // a step is announced before it runs
+        let first = steps.next().unwrap();
+        assert_eq!((first.index, first.label), (0, "print"));
+        assert_eq!(first.context, ctx);
+        assert!(trace.entries().is_empty());
+        let second = steps.next().unwrap();
+        assert_eq!((second.index, second.label), (1, "mark"));
+        assert_eq!(trace.entries(), vec!["4"]);
+
+        // the seq is split into its statements
+        let rest: Vec<_> = steps.map(|step| (step.label, step.context)).collect();
+        assert_eq!(rest, vec![("assign", ctx.clone()), ("nothing", HashMap::from([("x", 5)]))]);
+        assert_eq!(trace.entries(), vec!["4", "middle"]);
+    }
+
+    #[test]
+    fn stepper_can_stop_before_a_step() {
+        let trace = ExecutionTrace::new();
+        let mut program = block(vec![
+            Box::new(mark("first", trace.clone())),
+            Box::new(mark("second", trace.clone())),
+        ]);
+        let mut steps = program.steps(HashMap::new());
+        steps.next();
+        assert_eq!(steps.next().map(|step| step.label), Some("mark"));
+        drop(steps);
+        assert_eq!(trace.entries(), vec!["first"]);
+    }
+
+    #[test]
+    fn repeat_n_runs_runtime_count() {
+        let mut ctx = HashMap::new();
+        let trace = ExecutionTrace::new();
+        let mut program = repeat_n(3, mark("tick", trace.clone()));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["tick", "tick", "tick"]);
+        repeat_n(0, mark("never", trace.clone())).exec_stmt(&mut ctx);
+        assert_eq!(trace.entries().len(), 3);
+    }
+
+    #[test]
+    fn loop_index_counts_iterations() {
+        let mut ctx = HashMap::new();
+        let trace = ExecutionTrace::new();
+        repeat_n(3, print_to(loop_index(), trace.clone())).exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["0", "1", "2"]);
+        assert!(ctx.is_empty());
+        assert_eq!(loop_index().exec_expr(&ctx), 0);
+    }
+
+    #[test]
+    fn loop_index_of_innermost_loop() {
+        let mut ctx = HashMap::new();
+        let trace = ExecutionTrace::new();
+        let mut program = repeat::<2, _>(seq(
+            repeat_n(2, print_to(loop_index(), trace.clone())),
+            print_to(loop_index(), trace.clone()),
+        ));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["0", "1", "0", "0", "1", "1"]);
+
+        let trace = ExecutionTrace::new();
+        let mut program = while_loop(idiv(2u64, checked_add(loop_index(), 1u64)),
+                                     print_to(loop_index(), trace.clone()));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["0", "1"]);
+    }
+
+    #[test]
+    fn loop_index_is_not_in_context() {
+        let mut ctx = HashMap::from([("__loop_index", 7)]);
+        let trace = ExecutionTrace::new();
+        let mut program = repeat_n(2, seq(
+            assign("__loop_index", 9u64),
+            seq(print_to(loop_index(), trace.clone()),
+                print_to(constant("__loop_index"), trace.clone())),
+        ));
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["0", "9", "1", "9"]);
+        assert_eq!(ctx, HashMap::from([("__loop_index", 9)]));
+    }
+
+    #[test]
+    fn parse_program_runs_into_buffer() {
+        let mut ctx = HashMap::from([("x", 0), ("y", 9)]);
+        let trace = ExecutionTrace::new();
+        let mut program = parse_program_with(
+            "print 1; print when(constant(x), 2, constant(y));", trace.clone()).unwrap();
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["1", "9"]);
+    }
+
+    #[test]
+    fn parse_program_nested_repeat() {
+        let mut ctx = HashMap::from([("x", 4)]);
+        let trace = ExecutionTrace::new();
+        let src = "repeat 2 { print constant(x); repeat 2 { nothing; print 0 } }; print 1;;";
+        parse_program_with(src, trace.clone()).unwrap().exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["4", "0", "0", "4", "0", "0", "1"]);
+
+        // no ';' needed after a block
+        let trace = ExecutionTrace::new();
+        let src = "repeat 2 { repeat 1 { print 0 } print 1 } print 2";
+        parse_program_with(src, trace.clone()).unwrap().exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["0", "1", "0", "1", "2"]);
+    }
+
+    #[test]
+    fn parse_program_from_owned_source() {
+        let mut ctx = HashMap::from([("x", 3)]);
+        let trace = ExecutionTrace::new();
+        let src = format!("print constant({});", "x");
+        let mut program = parse_program_with(&src, trace.clone()).unwrap();
+        drop(src);
+        program.exec_stmt(&mut ctx);
+        assert_eq!(trace.entries(), vec!["3"]);
+        assert!(std::ptr::eq(intern("x"), intern(&String::from("x"))));
+    }
+
+    #[test]
+    fn parse_program_errors() {
+        let trace = ExecutionTrace::new();
+        let parse = |src| parse_program_with(src, trace.clone()).err();
+        assert_eq!(parse("print"), Some(ParseError::UnexpectedEnd));
+        assert_eq!(parse("print 1 print 2"), Some(ParseError::UnexpectedToken("print".into())));
+        assert_eq!(parse("shout 1;"), Some(ParseError::UnexpectedToken("shout".into())));
+        assert_eq!(parse("repeat x { }"), Some(ParseError::BadNumber("x".into())));
+        assert_eq!(parse("print 99999999999999999999;"),
+                   Some(ParseError::BadNumber("99999999999999999999".into())));
+        assert_eq!(parse("print when(1, 2);"), Some(ParseError::UnexpectedToken(")".into())));
+        assert_eq!(parse("nothing; }"), Some(ParseError::UnexpectedToken("}".into())));
+        assert_eq!(parse("repeat 1 { nothing;"), Some(ParseError::UnexpectedEnd));
+        assert_eq!(parse("print constant(;);"), Some(ParseError::UnexpectedToken(";".into())));
+        assert_eq!(parse("print constant(7);"), Some(ParseError::UnexpectedToken("7".into())));
+    }
+
+    #[test]
+    fn try_exec_reports_missing_name() {
+        let ctx = HashMap::from([("x", 1)]);
+        let mut program = when(constant("x"), checked_add(constant("missing"), 1u64), 0u64);
+        assert_eq!(program.try_exec_expr(&ctx), Err(RuntimeError::UndefinedName("missing")));
+        // only the branch taken is evaluated
+        let mut other = when(constant("x"), 2u64, constant("missing"));
+        assert_eq!(other.try_exec_expr(&ctx), Ok(Value::Int(2)));
+    }
+
+    #[test]
+    fn try_exec_covers_composite_nodes() {
+        let ctx = HashMap::from([("x", 1)]);
+        let missing = Err(RuntimeError::UndefinedName("missing"));
+        let options: Vec<Box<dyn TryExpr>> = vec![Box::new(5u64), Box::new(constant("missing"))];
+        let mut chosen = try_choose(constant("x"), options);
+        assert_eq!(chosen.try_exec_expr(&ctx), missing);
+        let mut chosen = try_choose(0u64, vec![Box::new(5u64)]);
+        assert_eq!(chosen.try_exec_expr(&ctx), Ok(Value::Int(5)));
+
+        let mut compared = less(constant("x"), constant("missing"));
+        assert_eq!(compared.try_exec_expr(&ctx), missing);
+        assert_eq!(less(constant("x"), 2u64).try_exec_expr(&ctx), Ok(Value::Bool(true)));
+
+        let mut piped = pipe(constant("missing"), vec![Box::new(|v| v + 1)]);
+        assert_eq!(piped.try_exec_expr(&ctx), missing);
+        let mut piped = pipe(constant("x"), vec![Box::new(|v| v + 1)]);
+        assert_eq!(piped.try_exec_expr(&ctx), Ok(Value::Int(2)));
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        assert_eq!(probe("p", log.clone(), constant("missing")).try_exec_expr(&ctx), missing);
+        assert_eq!(probe("p", log.clone(), constant("x")).try_exec_expr(&ctx), Ok(Value::Int(1)));
+        assert_eq!(*log.borrow(), vec![("p", Value::Int(1))]);
+
+        let arrays: VecContext = Rc::new(RefCell::new(HashMap::from([("xs", vec![1, 2])])));
+        let mut folded = fold(arrays.clone(), "xs", 0, checked_add(accumulator(), constant("y")));
+        assert_eq!(folded.try_exec_expr(&ctx), Err(RuntimeError::UndefinedName("y")));
+        let mut folded = fold(arrays, "xs", 10, checked_add(accumulator(), element()));
+        assert_eq!(folded.try_exec_expr(&ctx), Ok(Value::Int(13)));
+    }
+
+    #[test]
+    fn try_exec_memo_caches_only_success() {
+        let mut ctx = HashMap::new();
+        let mut memoized = memo(constant("x"));
+        assert_eq!(memoized.try_exec_expr(&ctx), Err(RuntimeError::UndefinedName("x")));
+        ctx.insert("x", 4);
+        assert_eq!(memoized.try_exec_expr(&ctx), Ok(Value::Int(4)));
+        ctx.insert("x", 5);
+        assert_eq!(memoized.try_exec_expr(&ctx), Ok(Value::Int(4)));
+    }
+
+    #[test]
+    fn try_exec_matches_exec() {
+        let ctx = HashMap::from([("x", 7), ("y", 3)]);
+        let mut e = checked_mul(modulo(constant("x"), constant("y")), idiv(constant("x"), 0u64));
+        assert_eq!(e.try_exec_expr(&ctx), Ok(e.exec_expr(&ctx)));
+        let mut e = checked_add(idiv(constant("x"), constant("y")), loop_index());
+        assert_eq!(e.try_exec_expr(&ctx), Ok(Value::Int(2)));
+    }
+
+    #[test]
+    fn try_exec_stmt_stops_at_first_error() {
+        let mut ctx = HashMap::new();
+        let trace = ExecutionTrace::new();
+        let mut program = seq(print_to(1u64, trace.clone()),
+                              repeat::<2, _>(print_to(constant("missing"), trace.clone())));
+        assert_eq!(program.try_exec_stmt(&mut ctx), Err(RuntimeError::UndefinedName("missing")));
+        assert_eq!(trace.entries(), vec!["1"]);
+
+        let trace = ExecutionTrace::new();
+        let mut program = try_block(vec![
+            Box::new(assign("x", 2u64)),
+            Box::new(print_to(constant("x"), trace.clone())),
+            Box::new(assign("y", constant("missing"))),
+            Box::new(print_to(3u64, trace.clone())),
+        ]);
+        assert_eq!(program.try_exec_stmt(&mut ctx), Err(RuntimeError::UndefinedName("missing")));
+        assert_eq!(trace.entries(), vec!["2"]);
+        assert_eq!(ctx.get("y"), None);
+    }
+
+    #[test]
+    fn try_exec_stmt_checks_loop_conditions() {
+        let mut ctx = HashMap::from([("x", 0)]);
+        let mut program = while_loop(constant("missing"), assign("x", 1u64));
+        assert_eq!(program.try_exec_stmt(&mut ctx), Err(RuntimeError::UndefinedName("missing")));
+        assert_eq!(ctx["x"], 0);
+
+        let mut program = repeat_until(5, constant("missing"),
+                                       assign("x", checked_add(constant("x"), 1u64)));
+        assert_eq!(program.try_exec_stmt(&mut ctx), Err(RuntimeError::UndefinedName("missing")));
+        assert_eq!(ctx["x"], 1);
+
+        let mut program = with_limit(3, repeat_n(10, assign("x",
+                                                             checked_add(constant("x"), 1u64))));
+        assert_eq!(program.try_exec_stmt(&mut ctx), Ok(()));
+        assert_eq!(ctx["x"], 4);
+    }
+
+    #[test]
+    fn try_exec_covers_stateful_nodes() {
+        let ctx = HashMap::from([("x", 3)]);
+        let mut saved = 7;
+        let result = save_in(&mut saved, constant("missing")).try_exec_expr(&ctx);
+        assert_eq!(result, Err(RuntimeError::UndefinedName("missing")));
+        assert_eq!(saved, 7);
+        assert_eq!(save_in(&mut saved, constant("x")).try_exec_expr(&ctx), Ok(Value::Int(3)));
+        assert_eq!(saved, 3);
+
+        let mut counter = 1;
+        let mut bump = volatile(&mut counter, "c", checked_add(constant("c"), constant("x")));
+        assert_eq!(bump.try_exec_expr(&ctx), Ok(Value::Int(4)));
+        assert_eq!(counter, 4);
+        let mut broken = volatile(&mut counter, "c", constant("missing"));
+        assert_eq!(broken.try_exec_expr(&ctx), Err(RuntimeError::UndefinedName("missing")));
+        assert_eq!(counter, 4);
+    }
+
+    #[test]
+    fn compile_seq_of_prints() {
+        let mut ctx = HashMap::new();
+        let walked = ExecutionTrace::new();
+        let mut program = seq(print_to(1u64, walked.clone()), print_to(2u64, walked.clone()));
+        program.exec_stmt(&mut ctx);
+
+        let ops = compile(&program).unwrap();
+        assert_eq!(ops, vec![Op::PushConst(1), Op::Print, Op::PushConst(2), Op::Print]);
+        let mut compiled = ExecutionTrace::new();
+        run_ops_to(&ops, &ctx, &mut compiled);
+        assert_eq!(compiled.entries(), walked.entries());
+    }
+
+    #[test]
+    fn compiled_program_matches_tree_walker() {
+        let mut ctx = HashMap::from([("x", 0), ("y", 7)]);
+        let walked = ExecutionTrace::new();
+        let mut program = block(vec![
+            Box::new(repeat::<2, _>(seq(
+                print_to(when(constant("x"), 1u64, modulo(constant("y"), 4u64)), walked.clone()),
+                repeat_n(2, print_to(checked_add(constant("y"), idiv(9u64, 2u64)),
+                                     walked.clone())),
+            ))),
+            Box::new(while_loop(constant("x"), nothing())),
+            Box::new(print_to(when(constant("y"), checked_mul(u64::MAX, 2u64), 0u64),
+                              walked.clone())),
+        ]);
+        program.exec_stmt(&mut ctx);
+        assert_eq!(walked.entries().len(), 7);
+
+        let ops = compile(&program).unwrap();
+        let mut compiled = ExecutionTrace::new();
+        run_ops_to(&ops, &ctx, &mut compiled);
+        assert_eq!(compiled.entries(), walked.entries());
+    }
+
+    #[test]
+    fn compile_rejects_unsupported_nodes() {
+        let trace = ExecutionTrace::new();
+        let program = seq(print(1u64), mark("m", trace));
+        assert_eq!(compile(&program), Err(Unsupported(std::any::type_name::<Mark>())));
+        assert!(compile(&print(loop_index())).is_err());
+    }
+
+    #[test]
+    fn random_exprs_compile_to_same_value() {
+        use rand::{SeedableRng, rngs::StdRng};
+        let ctx = HashMap::from([("x", 0), ("y", 7), ("z", u64::MAX)]);
+        for seed in 0..100 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut expr = random_expr(&mut rng, &["x", "y", "z"], 4);
+            let walked = expr.exec_expr(&ctx);
+            let ops = compile(&print(expr)).unwrap();
+            let mut out = Vec::new();
+            run_ops_to(&ops, &ctx, &mut out);
+            assert_eq!(String::from_utf8(out).unwrap(), format!("{}\n", walked), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn random_expr_is_deterministic_per_seed() {
+        use rand::{SeedableRng, rngs::StdRng};
+        let ctx = HashMap::from([("k", 3)]);
+        for seed in 0..20 {
+            let mut a = random_expr(&mut StdRng::seed_from_u64(seed), &["k"], 5);
+            let mut b = random_expr(&mut StdRng::seed_from_u64(seed), &["k"], 5);
+            assert_eq!(a.exec_expr(&ctx), b.exec_expr(&ctx));
+        }
+        let mut literal = random_expr(&mut StdRng::seed_from_u64(1), &[], 0);
+        assert!(literal.exec_expr(&HashMap::new()).as_int() < 5);
+    }
+
+    #[test]
+    fn random_exprs_simplify_to_same_value() {
+        use rand::{SeedableRng, rngs::StdRng};
+        let ctx = HashMap::from([("x", 0), ("y", 7), ("z", u64::MAX)]);
+        let random = |seed| random_expr(&mut StdRng::seed_from_u64(seed), &["x", "y", "z"], 4);
+        for seed in 0..100 {
+            let plain = random(seed).exec_expr(&ctx);
+            assert_eq!(random(seed).simplify().exec_expr(&ctx), plain, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn simplify_folds_literal_subexpressions() {
+        let e = when(modulo(4u64, 2u64), constant("x"), checked_add(2u64, 3u64));
+        let e: Box<dyn Expr> = Box::new(e);
+        assert_eq!(e.simplify().literal(), Some(5));
+        let e: Box<dyn Expr> = Box::new(checked_mul(constant("x"), idiv(9u64, 3u64)));
+        let mut simplified = e.simplify();
+        assert_eq!(simplified.literal(), None);
+        assert_eq!(simplified.exec_expr(&HashMap::from([("x", 2)])), 6);
+    }
+
     // Two integration tests that exercise everything
     #[test]
     fn integration_full_flow_1() {
-        let ctx = HashMap::from([("x", 0), ("y", 10)]);
+        let mut ctx = HashMap::from([("x", 0), ("y", 10)]);
         let log = Rc::new(RefCell::new(Vec::new()));
         let mut a: u64 = 0;
         let b: u64 = 0;
@@ -451,13 +3329,13 @@ mod tests {
             print(when(constant("y"), 1u64, 2u64)),
             print(when(constant("x"), 1u64, 2u64)),
         );
-        part1.exec_stmt(&ctx);
+        part1.exec_stmt(&mut ctx);
 
         // part2: save into a, then read a in a separate step to avoid borrow conflicts
         let mut part2a = print(save_in(&mut a, when(constant("y"), 7u64, 8u64)));
-        part2a.exec_stmt(&ctx);
+        part2a.exec_stmt(&mut ctx);
         let mut part2b = print(read_from(&a));
-        part2b.exec_stmt(&ctx);
+        part2b.exec_stmt(&mut ctx);
 
         // part3
         let mut part3 = seq(
@@ -468,7 +3346,7 @@ mod tests {
             // Use `a` (currently 7) to shadow `y`, so branch -> 100
             print(volatile(&mut a, "y", when(constant("y"), 100u64, 200u64))),
         );
-        part3.exec_stmt(&ctx);
+        part3.exec_stmt(&mut ctx);
 
         assert_eq!(a, 100);
         assert_eq!(b, 0);
@@ -477,7 +3355,7 @@ mod tests {
 
     #[test]
     fn integration_full_flow_2() {
-        let ctx = HashMap::from([("x", 1), ("y", 0)]);
+        let mut ctx = HashMap::from([("x", 1), ("y", 0)]);
         let log = Rc::new(RefCell::new(Vec::new()));
         let mut a: u64 = 0;
         let mut b: u64 = 0;
@@ -504,7 +3382,7 @@ mod tests {
                 log: log.clone(),
             }),
         );
-        program.exec_stmt(&ctx);
+        program.exec_stmt(&mut ctx);
 
         assert_eq!(a, 1);
         assert_eq!(b, 123);
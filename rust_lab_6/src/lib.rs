@@ -1,3 +1,6 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
 use itertools::Itertools;
 
 // Nie zmieniaj ciała tej funkcji — jedynie typy.
@@ -16,6 +19,27 @@ pub fn make_counter(start: i64) -> impl FnMut() -> i64 {
     }
 }
 
+// Oba domknięcia dzielą jedną wartość, wywołanie któregokolwiek przesuwa licznik dla obu.
+pub fn make_shared_counter(start: i64) -> (impl FnMut() -> i64, impl FnMut() -> i64) {
+    let shared = Rc::new(Cell::new(start));
+    let advance = |counter: Rc<Cell<i64>>| move || counter.replace(counter.get() + 1);
+    (advance(shared.clone()), advance(shared))
+}
+
+pub fn make_bounded_counter(start: i64, max: i64) -> impl FnMut() -> Option<i64> {
+    let mut next = Some(start).filter(|&x| x <= max);
+    move || {
+        let value = next?;
+        next = value.checked_add(1).filter(|&x| x <= max);
+        Some(value)
+    }
+}
+
+// Kończy się dopiero na i64::MAX zamiast się przepełnić.
+pub fn counter_iter(start: i64) -> impl Iterator<Item = i64> {
+    std::iter::successors(Some(start), |&x| x.checked_add(1))
+}
+
 pub fn sum_squares_odd_loop(list: &[u32]) -> u32 {
     let mut sum = 0;
     for &x in list {
@@ -45,12 +69,35 @@ pub fn vertices_loop(edges: &[(u32, u32)]) -> Vec<u32> {
 }
 
 pub fn vertices(edges: &[(u32, u32)]) -> Vec<u32> {
-    let mut vertices: Vec<u32> = edges.iter().flat_map(|&(x, y)| [x,y]).collect();
+    vertices_iter(edges.iter().copied())
+}
+
+pub fn vertices_iter<I: IntoIterator<Item = (u32, u32)>>(edges: I) -> Vec<u32> {
+    let mut vertices: Vec<u32> = edges.into_iter().flat_map(|(x, y)| [x,y]).collect();
     vertices.sort();
     vertices.dedup();
     vertices
 }
 
+// Krawędzie z wierzchołkami ponumerowanymi gęsto 0..k oraz posortowany wektor
+// indeks -> oryginalny wierzchołek.
+pub fn remap_vertices(edges: &[(u32, u32)]) -> (Vec<(usize, usize)>, Vec<u32>) {
+    let ids = vertices(edges);
+    let index = |v: u32| ids.binary_search(&v).expect("każdy wierzchołek krawędzi jest w ids");
+    let remapped = edges.iter().map(|&(x, y)| (index(x), index(y))).collect();
+    (remapped, ids)
+}
+
+// Wierzchołek -> (stopień wejściowy, stopień wyjściowy); pętla liczy się do obu.
+pub fn degrees(edges: &[(u32, u32)]) -> BTreeMap<u32, (u32, u32)> {
+    let mut degrees = BTreeMap::new();
+    for &(from, to) in edges {
+        degrees.entry(from).or_insert((0, 0)).1 += 1;
+        degrees.entry(to).or_insert((0, 0)).0 += 1;
+    }
+    degrees
+}
+
 // Zwraca posortowany rosnąco wektor wierzchołków uczestniczących w jakimkolwiek
 // cyklu długości 2 (u->v oraz v->u, u!=v), bez duplikatów.
 pub fn cycles_2_loop(edges: &[(u32, u32)]) -> Vec<u32> {
@@ -69,15 +116,31 @@ pub fn cycles_2_loop(edges: &[(u32, u32)]) -> Vec<u32> {
 }
 
 pub fn cycles_2(edges: &[(u32, u32)]) -> Vec<u32> {
-    let mut vertices: Vec<u32> = edges.iter().copied()
-        .cartesian_product(edges.iter().copied())
-        .filter(|&(x,y)| x.0 == y.1 && x.1 == y.0 && x.1 != x.0)
-        .flat_map(|(x, _)| [x.0, x.1]).collect();
+    cycles_2_iter(edges.iter().copied())
+}
+
+// Każda krawędź jest odwiedzana raz, krawędzi odwrotnej szukamy w zbiorze.
+pub fn cycles_2_iter<I: IntoIterator<Item = (u32, u32)>>(edges: I) -> Vec<u32> {
+    let edges: BTreeSet<(u32, u32)> = edges.into_iter().collect();
+    let mut vertices: Vec<u32> = edges.iter()
+        .filter(|&&(x, y)| x != y && edges.contains(&(y, x)))
+        .flat_map(|&(x, y)| [x, y]).collect();
     vertices.sort();
     vertices.dedup();
     vertices
 }
 
+// Pary (min, max) wierzchołków tworzących cykl długości 2, posortowane i bez duplikatów.
+pub fn cycles_2_pairs(edges: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut pairs: Vec<(u32, u32)> = edges.iter().copied()
+        .cartesian_product(edges.iter().copied())
+        .filter(|&(x,y)| x.0 == y.1 && x.1 == y.0 && x.1 != x.0)
+        .map(|(x, _)| (x.0.min(x.1), x.0.max(x.1))).collect();
+    pairs.sort();
+    pairs.dedup();
+    pairs
+}
+
 pub fn primes_loop(n: u32) -> Vec<u32> {
     let mut vec = Vec::new();
     for i in 2..n {
@@ -174,6 +237,47 @@ mod tests {
         assert_eq!(c(), 13); // niezależne liczniki
     }
 
+    #[test]
+    fn shared_counter_interleaved() {
+        let (mut a, mut b) = make_shared_counter(5);
+        let seen = vec![a(), b(), b(), a(), b()];
+        assert_eq!(seen, vec![5, 6, 7, 8, 9]);
+        let (mut c, _) = make_shared_counter(0);
+        assert_eq!(c(), 0);
+        assert_eq!(a(), 10); // osobne wywołania make_shared_counter są niezależne
+    }
+
+    #[test]
+    fn bounded_counter_stops_at_max() {
+        let mut c = make_bounded_counter(3, 5);
+        let mut c2 = make_bounded_counter(-1, 0);
+        assert_eq!(c(), Some(3));
+        assert_eq!(c2(), Some(-1));
+        assert_eq!(c(), Some(4));
+        assert_eq!(c(), Some(5));
+        assert_eq!(c(), None);
+        assert_eq!(c(), None);
+        assert_eq!(c2(), Some(0)); // niezależne liczniki
+        assert_eq!(c2(), None);
+    }
+
+    #[test]
+    fn bounded_counter_edges() {
+        let mut empty = make_bounded_counter(1, 0);
+        assert_eq!(empty(), None);
+        let mut top = make_bounded_counter(i64::MAX - 1, i64::MAX);
+        assert_eq!(top(), Some(i64::MAX - 1));
+        assert_eq!(top(), Some(i64::MAX));
+        assert_eq!(top(), None);
+    }
+
+    #[test]
+    fn counter_iter_take() {
+        assert_eq!(counter_iter(10).take(3).collect::<Vec<_>>(), vec![10, 11, 12]);
+        assert_eq!(counter_iter(-2).nth(4), Some(2));
+        assert_eq!(counter_iter(i64::MAX - 1).collect::<Vec<_>>(), vec![i64::MAX - 1, i64::MAX]);
+    }
+
     #[test]
     fn sum_squares_odd_cases() {
         let empty: &[u32] = &[];
@@ -198,6 +302,44 @@ mod tests {
         let c_iter = cycles_2(&edges);
         assert_eq!(c_loop, c_iter);
         assert_eq!(c_loop, vec![1, 2, 3, 4]);
+        assert_eq!(cycles_2_pairs(&edges), vec![(1, 2), (3, 4)]);
+    }
+
+    #[test]
+    fn iter_variants_match_slices() {
+        let edges: Vec<(u32, u32)> = (0..10).map(|i| (i % 4, (i * 3) % 5)).collect();
+        assert_eq!(vertices_iter((0..10).map(|i| (i % 4, (i * 3) % 5))), vertices(&edges));
+        assert_eq!(cycles_2_iter((0..10).map(|i| (i % 4, (i * 3) % 5))), cycles_2(&edges));
+        assert_eq!(cycles_2_iter((0..10).map(|i| (i % 4, (i * 3) % 5))), cycles_2_loop(&edges));
+        assert_eq!(cycles_2_iter([(1, 2), (2, 1), (3, 3)]), vec![1, 2]);
+    }
+
+    #[test]
+    fn cycles_2_pairs_share_vertex() {
+        let edges = [(2, 1), (1, 2), (1, 2), (3, 1), (1, 3), (4, 4)];
+        assert_eq!(cycles_2_pairs(&edges), vec![(1, 2), (1, 3)]);
+        assert_eq!(cycles_2(&edges), vec![1, 2, 3]);
+        assert!(cycles_2_pairs(&[]).is_empty());
+    }
+
+    #[test]
+    fn remap_sparse_vertices() {
+        let edges = [(5, 100), (100, 7), (7, 5), (100, 100)];
+        let (remapped, ids) = remap_vertices(&edges);
+        assert_eq!(ids, vec![5, 7, 100]);
+        assert_eq!(remapped, vec![(0, 2), (2, 1), (1, 0), (2, 2)]);
+        let restored: Vec<(u32, u32)> = remapped.iter().map(|&(x, y)| (ids[x], ids[y])).collect();
+        assert_eq!(restored, edges);
+        assert_eq!(remap_vertices(&[]), (vec![], vec![]));
+    }
+
+    #[test]
+    fn degrees_with_self_loop_and_sink() {
+        let edges = [(1, 2), (2, 3), (1, 3), (4, 4), (2, 1)];
+        let d = degrees(&edges);
+        assert_eq!(d, BTreeMap::from([(1, (1, 2)), (2, (1, 2)), (3, (2, 0)), (4, (1, 1))]));
+        assert_eq!(d.keys().copied().collect::<Vec<_>>(), vertices(&edges));
+        assert!(degrees(&[]).is_empty());
     }
 
     #[test]
@@ -205,6 +347,7 @@ mod tests {
         let edges = [(1, 2), (2, 1), (1, 2), (2, 1), (2, 2)];
         assert_eq!(cycles_2_loop(&edges), vec![1, 2]);
         assert_eq!(cycles_2(&edges), vec![1, 2]);
+        assert_eq!(cycles_2_pairs(&edges), vec![(1, 2)]);
     }
 
     #[test]
@@ -2,7 +2,7 @@ use std::cell::{Cell, OnceCell, LazyCell, RefCell};
 use std::rc::{Rc, Weak};
 use std::ops::{Deref, DerefMut};
 use std::borrow::Cow;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::{fs, io};
 use std::path::{Path, PathBuf};
 
@@ -65,6 +65,16 @@ impl<T> DerefMut for HeapOrStack<T> {
     }
 }
 
+impl<T> HeapOrStack<T> {
+    // The result lives where the original did.
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> HeapOrStack<U> {
+        match self {
+            HeapOrStack::Stack(v) => HeapOrStack::Stack(f(v)),
+            HeapOrStack::Heap(b) => HeapOrStack::Heap(Box::new(f(*b))),
+        }
+    }
+}
+
 pub fn canon_head<'a>(xs: &'a VecDeque<i32>)
     -> Option<Cow<'a, VecDeque<i32>>> {
     if xs.is_empty() {
@@ -129,8 +139,16 @@ impl SharedFile {
     pub fn get(&self) -> &str {
         &self.file
     }
+
+    // Doesn't force the read.
+    pub fn is_initialized(&self) -> bool {
+        LazyCell::get(&self.file).is_some()
+    }
 }
 
+#[derive(Debug, PartialEq)]
+pub struct CycleError;
+
 pub struct Vertex {
     pub out_edges_owned: Vec<Rc<RefCell<Vertex>>>,
     pub out_edges: Vec<Weak<RefCell<Vertex>>>,
@@ -186,6 +204,77 @@ impl Vertex {
 
         head
     }
+
+    // Vertex i gets data i, every edge is a weak link so the Vec owns all vertices.
+    pub fn from_edges(n: usize, edges: &[(usize, usize)]) -> Vec<Rc<RefCell<Vertex>>> {
+        let vertices: Vec<Rc<RefCell<Vertex>>> = (0..n).map(|i| {
+            let vertex = Rc::new(RefCell::new(Vertex::new()));
+            vertex.borrow_mut().data = i as i32;
+            vertex
+        }).collect();
+        for &(from, to) in edges {
+            vertices[from].borrow_mut().link_to(&vertices[to]);
+        }
+        vertices
+    }
+
+    pub fn has_cycle(roots: &[Rc<RefCell<Vertex>>]) -> bool {
+        Self::topo_sort(roots).is_err()
+    }
+
+    pub fn topo_sort(roots: &[Rc<RefCell<Vertex>>]) -> Result<Vec<i32>, CycleError> {
+        let mut finished = HashMap::new();
+        let mut order = Vec::new();
+        for root in roots {
+            Self::visit(root, &mut finished, &mut order)?;
+        }
+        order.reverse();
+        Ok(order)
+    }
+
+    // Level d holds the vertices first reached after d steps, each vertex appears only once.
+    pub fn nodes_by_depth(start: &Rc<RefCell<Vertex>>, max_depth: usize) -> Vec<Vec<i32>> {
+        let mut visited = HashSet::from([Rc::as_ptr(start)]);
+        let mut frontier = vec![start.clone()];
+        let mut levels = Vec::new();
+
+        for _ in 0..=max_depth {
+            levels.push(frontier.iter().map(|v| v.borrow().data).collect());
+            let mut next_frontier = Vec::new();
+            for vertex in &frontier {
+                for weak in vertex.borrow().all_neighbours() {
+                    if let Some(next) = weak.upgrade()
+                        && visited.insert(Rc::as_ptr(&next)) {
+                        next_frontier.push(next);
+                    }
+                }
+            }
+            frontier = next_frontier;
+        }
+        levels
+    }
+
+    // finished[v] is false while v is on the DFS stack, true once all its successors are done.
+    fn visit(vertex: &Rc<RefCell<Vertex>>, finished: &mut HashMap<*const RefCell<Vertex>, bool>,
+             order: &mut Vec<i32>) -> Result<(), CycleError> {
+        match finished.get(&Rc::as_ptr(vertex)) {
+            Some(true) => return Ok(()),
+            Some(false) => return Err(CycleError),
+            None => {}
+        }
+        finished.insert(Rc::as_ptr(vertex), false);
+
+        let neighbours = vertex.borrow().all_neighbours();
+        for weak in neighbours {
+            if let Some(next) = weak.upgrade() {
+                Self::visit(&next, finished, order)?;
+            }
+        }
+
+        finished.insert(Rc::as_ptr(vertex), true);
+        order.push(vertex.borrow().data);
+        Ok(())
+    }
 }
 
 impl Default for Vertex {
@@ -208,6 +297,8 @@ fn main() {
     *a += 5;
     *b += 7;
     println!("a = {}, b = {}", *a, *b);
+    let b = b.map(|x| format!("<{}>", x));
+    println!("b mapped = {}", *b);
 
     // Exercise 5
     let file_cache = CachedFile::new();
@@ -227,7 +318,7 @@ fn main() {
     let _ = fs::write(&path, "Shared test data");
     let file_ref1 = SharedFile::new(path.clone());
     let file_ref2 = file_ref1.clone();
-    println!("Refs created, but file not read yet.");
+    println!("Refs created, but file not read yet: {}", !file_ref1.is_initialized());
     println!("Content (ref2): {}", file_ref2.get());
     println!("Content (ref1): {}", file_ref1.get());
     let _ = fs::remove_file(path);
@@ -252,4 +343,89 @@ fn main() {
             }
         }
     }
+    println!("Topological sort of the cycle: {:?}", Vertex::topo_sort(&[cycle_head]));
+
+    let dag = Vertex::from_edges(4, &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+    println!("DAG has cycle: {}, topological order: {:?}",
+             Vertex::has_cycle(&dag), Vertex::topo_sort(&dag));
+    println!("DAG levels from v0: {:?}", Vertex::nodes_by_depth(&dag[0], 3));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(order: &[i32], data: i32) -> usize {
+        order.iter().position(|&d| d == data).unwrap()
+    }
+
+    #[test]
+    fn shared_file_reports_initialization() {
+        let path = std::env::temp_dir().join(format!("rust_lab_7_{}_shared", std::process::id()));
+        fs::write(&path, "lazy").unwrap();
+        let file = SharedFile::new(path.clone());
+        let other = file.clone();
+        assert!(!file.is_initialized());
+        assert!(!other.is_initialized());
+        assert_eq!(other.get(), "lazy");
+        assert!(file.is_initialized());
+        assert!(other.is_initialized());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn topo_sort_orders_dag() {
+        let edges = [(0, 1), (0, 2), (1, 3), (2, 3), (4, 2)];
+        let dag = Vertex::from_edges(5, &edges);
+        let order = Vertex::topo_sort(&dag).unwrap();
+        assert_eq!(order.len(), 5);
+        for (from, to) in edges {
+            assert!(position(&order, from as i32) < position(&order, to as i32));
+        }
+        assert!(!Vertex::has_cycle(&dag));
+    }
+
+    #[test]
+    fn topo_sort_only_reaches_from_roots() {
+        let dag = Vertex::from_edges(4, &[(0, 1), (2, 3)]);
+        assert_eq!(Vertex::topo_sort(&dag[..1]), Ok(vec![0, 1]));
+    }
+
+    #[test]
+    fn topo_sort_rejects_cycle() {
+        let cycle = Vertex::cycle(3);
+        assert_eq!(Vertex::topo_sort(std::slice::from_ref(&cycle)), Err(CycleError));
+        assert!(Vertex::has_cycle(&[cycle]));
+    }
+
+    #[test]
+    fn nodes_by_depth_on_cycle() {
+        let cycle = Vertex::cycle(4);
+        let levels = Vertex::nodes_by_depth(&cycle, 5);
+        assert_eq!(levels, vec![vec![0], vec![1], vec![2], vec![3], vec![], vec![]]);
+    }
+
+    #[test]
+    fn nodes_by_depth_fans_out_once() {
+        let graph = Vertex::from_edges(5, &[(0, 1), (0, 2), (1, 3), (2, 3), (3, 0), (2, 4)]);
+        let mut levels = Vertex::nodes_by_depth(&graph[0], 2);
+        levels[1].sort();
+        levels[2].sort();
+        assert_eq!(levels, vec![vec![0], vec![1, 2], vec![3, 4]]);
+        assert_eq!(Vertex::nodes_by_depth(&graph[0], 0), vec![vec![0]]);
+    }
+
+    #[test]
+    fn topo_sort_rejects_self_loop() {
+        let graph = Vertex::from_edges(2, &[(0, 1), (1, 1)]);
+        assert_eq!(Vertex::topo_sort(&graph), Err(CycleError));
+    }
+
+    #[test]
+    fn heap_or_stack_map_keeps_variant() {
+        let stack = HeapOrStack::Stack(2).map(|x| x * 10);
+        assert!(matches!(stack, HeapOrStack::Stack(20)));
+        let heap = HeapOrStack::Heap(Box::new(2)).map(|x| x.to_string());
+        assert!(matches!(&heap, HeapOrStack::Heap(b) if **b == "2"));
+    }
 }